@@ -0,0 +1,107 @@
+// ext libs
+use hyper;
+use hyper::server::{Server, Request, Response, Handler, Listening};
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use serde_json;
+// std
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+// internal
+use event::Event;
+use metrics;
+use status::Status;
+
+/// Embedded http server, which shows dispatcher status and allows to
+/// control it:
+///
+/// * GET /state -- dispatcher status
+/// * GET /spec -- spec of currently running deploy
+/// * GET /events -- recently received events
+/// * GET /metrics -- metrics in prometheus text format
+/// * POST /redeploy -- redeploy current spec
+/// * POST /rollback -- abort deploy of candidate and return to last stable
+pub struct Api {
+    status: Arc<Mutex<Option<Status>>>,
+    // mpsc::Sender is not Sync, so it can't be shared between handler threads
+    events: Mutex<mpsc::Sender<Event>>
+}
+
+impl Api {
+    fn reply(&self, method: &Method, path: &str) -> (StatusCode, String) {
+        let status = self.status.lock().unwrap();
+        match (method, path) {
+            (&Method::Get, "/state") => match *status {
+                Some(ref status) => to_json(status),
+                None => (StatusCode::ServiceUnavailable,
+                         "No status yet".to_string())
+            },
+            (&Method::Get, "/spec") => match *status {
+                Some(Status{spec: Some(ref spec), ..}) => to_json(spec),
+                _ => (StatusCode::NotFound, "No running spec".to_string())
+            },
+            (&Method::Get, "/events") => match *status {
+                Some(ref status) => to_json(&status.events),
+                None => to_json(&Vec::<String>::new())
+            },
+            (&Method::Get, "/metrics") => (StatusCode::Ok, metrics::render()),
+            (&Method::Post, "/redeploy") => self.send(Event::Redeploy),
+            (&Method::Post, "/rollback") => self.send(Event::Rollback),
+            _ => (StatusCode::NotFound, "Not found".to_string())
+        }
+    }
+
+    fn send(&self, event: Event) -> (StatusCode, String) {
+        match self.events.lock().unwrap().send(event) {
+            Ok(_) => (StatusCode::Accepted, "Accepted".to_string()),
+            Err(_) => (StatusCode::ServiceUnavailable,
+                       "Dispatcher is not running".to_string())
+        }
+    }
+}
+
+fn to_json<T: ::serde::Serialize>(value: &T) -> (StatusCode, String) {
+    match serde_json::to_string(value) {
+        Ok(json) => (StatusCode::Ok, json),
+        Err(e) => (StatusCode::InternalServerError, format!("{}", e))
+    }
+}
+
+impl Handler for Api {
+    fn handle<'a, 'k>(&'a self, req: Request<'a, 'k>, mut res: Response<'a>) {
+        let (code, body) = match req.uri {
+            RequestUri::AbsolutePath(ref path) => {
+                // ignore query string
+                let path = path.split('?').next().unwrap_or("");
+                self.reply(&req.method, path)
+            },
+            _ => (StatusCode::BadRequest, "Bad request".to_string())
+        };
+        debug!("{} {} -> {}", req.method, req.uri, code);
+        *res.status_mut() = code;
+        if let Err(e) = res.send(body.as_bytes()) {
+            warn!("Can't send api response: {}", e);
+        }
+    }
+}
+
+/// Starts api server on given address; statuses are kept up to date from
+/// dispatcher's subscription, requests are sent to dispatcher as events
+pub fn serve(addr: &str, statuses: mpsc::Receiver<Status>,
+             events: mpsc::Sender<Event>) -> Result<Listening, hyper::Error>
+{
+    let status = Arc::new(Mutex::new(None));
+    let thread_status = status.clone();
+    thread::spawn(move || {
+        for new_status in statuses.iter() {
+            *thread_status.lock().unwrap() = Some(new_status);
+        }
+    });
+    let api = Api{
+        status: status,
+        events: Mutex::new(events)
+    };
+    let server = try!(Server::http(addr));
+    server.handle(api)
+}
@@ -0,0 +1,203 @@
+// ext libs
+use argparse;
+use log;
+use serde_json;
+// traits
+use std::io::Read;
+// std
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io;
+// internal
+use config::Config;
+use consul::{Consul, ServiceRegistration};
+use docker::{self, ContainerConfig};
+use interpolate::{HostResolver, interpolate_spec, resolve_secrets, resolve_discoveries};
+use spec::{Spec, Format};
+use utils::*;
+
+const FORMAT_HELP: &'static str = "Spec format: json, yaml or toml; guessed \
+from file extension or content by default";
+
+/// Parses subcommand arguments; `args` should start with program name
+fn parse_args(ap: &argparse::ArgumentParser, args: Vec<String>) -> Result<(), i32> {
+    ap.parse(args, &mut io::stdout(), &mut io::stderr())
+}
+
+/// Reads whole file, or stdin, when path is "-"
+fn read_input(path: &str) -> Result<String, String> {
+    let mut content = String::new();
+    let result = if path == "-" {
+        io::stdin().read_to_string(&mut content)
+    } else {
+        File::open(path).and_then(|mut f| f.read_to_string(&mut content))
+    };
+    match result {
+        Ok(_) => Ok(content),
+        Err(e) => Err(format!("Can't read {}: {}", path, error_details(&e)))
+    }
+}
+
+/// `condo validate [spec]`: checks spec and returns non-zero exit code,
+/// when it is invalid
+pub fn validate(args: Vec<String>) -> i32 {
+    let mut path = "-".to_string();
+    let mut opt_format:Option<Format> = None;
+    {
+        let mut ap = argparse::ArgumentParser::new();
+        ap.set_description("Check spec and exit with non-zero code, \
+if it is invalid.");
+        ap.refer(&mut path)
+            .add_argument("spec", argparse::Store,
+                          "Path to spec; stdin is read, when omitted or -");
+        ap.refer(&mut opt_format)
+            .add_option(&["--format"], argparse::StoreOption,
+                        FORMAT_HELP);
+        if let Err(code) = parse_args(&ap, args) {
+            return code;
+        }
+    }
+    let content = match read_input(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            println_err!("{}", e);
+            return 2;
+        }
+    };
+    let format = opt_format.or(Format::from_path(&path));
+    let spec = match Spec::parse(&content, format) {
+        Ok(spec) => spec,
+        Err(e) => {
+            println_err!("Can't parse spec: {}", e);
+            return 1;
+        }
+    };
+    match spec.validate() {
+        Ok(_) => {
+            println!("Spec is valid, fingerprint: {}", spec.fingerprint());
+            0
+        },
+        Err(errors) => {
+            println_err!("{}", errors);
+            1
+        }
+    }
+}
+
+/// Everything condo would send to docker and consul for spec
+#[derive(Serialize, Debug)]
+struct Rendered {
+    pull: BTreeMap<String, String>,
+    name: Option<String>,
+    create: ContainerConfig,
+    services: Vec<ServiceRegistration>,
+    discoveries: BTreeMap<String, String>
+}
+
+fn render_spec(template: &Spec, consul: Consul) -> Result<Rendered, String> {
+    let resolver = HostResolver::new(consul);
+    let spec = &try!(interpolate_spec(template, &resolver)
+                     .map_err(|e| format!("Can't interpolate spec: {}", e)));
+    let discovered = try!(resolve_discoveries(spec, &resolver)
+                          .map_err(|e| format!("Can't resolve discovery {}", e)));
+    let secrets = try!(resolve_secrets(spec, &resolver)
+                       .map_err(|e| format!("Can't resolve secret: {}", e)));
+    Ok(Rendered{
+        pull: docker::pull_params(&spec.image).into_iter()
+            .map(|(k, v)| (k.to_string(), v))
+            .collect(),
+        name: docker::container_name(spec),
+        // secrets are resolved to check they exist, but never printed
+        create: ContainerConfig::from_spec(spec, &template.fingerprint(), &discovered,
+                                           &secrets).redacted(),
+        services: spec.services.iter()
+            // dynamic host ports are known only after container is started
+            .map(|service| ServiceRegistration::from_service(spec, service, None))
+            .collect(),
+        discoveries: discovered.into_iter().collect()
+    })
+}
+
+/// `condo render [--key key | spec]`: prints docker and consul requests,
+/// which would be made for spec, without contacting docker
+pub fn render(args: Vec<String>) -> i32 {
+    let mut path = "-".to_string();
+    let mut opt_key:Option<String> = None;
+    let mut consul_endpoint = "127.0.0.1:8500".to_string();
+    let mut opt_consul_token:Option<String> = None;
+    let mut opt_format:Option<Format> = None;
+    {
+        let mut ap = argparse::ArgumentParser::new();
+        ap.set_description("Print container create request, pull params, \
+consul service registrations and discovered env vars for spec. Consul is \
+queried to resolve discoveries, docker is not contacted.");
+        ap.refer(&mut consul_endpoint)
+            .envvar("CONSUL_AGENT")
+            .add_option(&["--consul"], argparse::Store,
+                        "Address of consul agent to query");
+        ap.refer(&mut opt_consul_token)
+            .envvar("CONSUL_TOKEN")
+            .add_option(&["--consul-token"], argparse::StoreOption,
+                        "ACL token for consul requests; can be set via \
+CONSUL_TOKEN env var");
+        ap.refer(&mut opt_key)
+            .add_option(&["--key"], argparse::StoreOption,
+                        "Read spec from consul key instead of file");
+        ap.refer(&mut path)
+            .add_argument("spec", argparse::Store,
+                          "Path to spec; stdin is read, when omitted or -");
+        ap.refer(&mut opt_format)
+            .add_option(&["--format"], argparse::StoreOption,
+                        FORMAT_HELP);
+        if let Err(code) = parse_args(&ap, args) {
+            return code;
+        }
+    }
+    let consul = Consul::from_config(&Config{
+        consul: consul_endpoint,
+        consul_token: opt_consul_token,
+        // docker is not contacted
+        docker: String::new(),
+        log_level: log::LogLevelFilter::Off
+    });
+    let content = match opt_key {
+        Some(ref key) => consul.read_key(key)
+            .map_err(|e| format!("Can't read consul key {}: {}", key, e)),
+        None => read_input(&path)
+    };
+    let content = match content {
+        Ok(content) => content,
+        Err(e) => {
+            println_err!("{}", e);
+            return 2;
+        }
+    };
+    let format = match opt_key {
+        Some(_) => opt_format,
+        None => opt_format.or(Format::from_path(&path))
+    };
+    let spec = match Spec::parse(&content, format) {
+        Ok(spec) => spec,
+        Err(e) => {
+            println_err!("Can't parse spec: {}", e);
+            return 1;
+        }
+    };
+    let rendered = match render_spec(&spec, consul) {
+        Ok(rendered) => rendered,
+        Err(e) => {
+            println_err!("{}", e);
+            return 1;
+        }
+    };
+    match serde_json::to_string_pretty(&rendered) {
+        Ok(json) => {
+            println!("{}", json);
+            0
+        },
+        Err(e) => {
+            println_err!("Can't serialize result: {}", e);
+            1
+        }
+    }
+}
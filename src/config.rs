@@ -0,0 +1,88 @@
+// ext libs
+use log;
+use serde_json;
+// traits
+use std::io::Read;
+use std::fmt;
+use std::error;
+use std::str::FromStr;
+// std
+use std::fs::File;
+use std::path::Path;
+// internal
+use utils::*;
+
+/// Settings, which can be changed without restart
+#[derive(Clone, Debug)]
+pub struct Config {
+    pub consul: String,
+    pub consul_token: Option<String>,
+    pub docker: String,
+    pub log_level: log::LogLevelFilter
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    IOError(String), // can't read file
+    ParseError(String) // invalid json / values
+}
+
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        match self {
+            &ConfigError::IOError(ref s) => s,
+            &ConfigError::ParseError(ref s) => s
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &ConfigError::IOError(ref s) =>
+                write!(f, "Can't read config: {}", s),
+            &ConfigError::ParseError(ref s) =>
+                write!(f, "Invalid config: {}", s)
+        }
+    }
+}
+
+// All fields are optional: missing ones are taken from command line
+#[derive(Deserialize, Debug)]
+struct ConfigFile {
+    #[serde(default)]
+    consul: Option<String>,
+    #[serde(default)]
+    consul_token: Option<String>,
+    #[serde(default)]
+    docker: Option<String>,
+    #[serde(default)]
+    loglevel: Option<String>
+}
+
+impl Config {
+    /// Returns config with settings from json file applied on top of self
+    pub fn with_file<P: AsRef<Path>>(&self, path: P) -> Result<Config, ConfigError> {
+        let mut content = String::new();
+        try!(File::open(path.as_ref())
+             .and_then(|mut f| f.read_to_string(&mut content))
+             .map_err(|e| ConfigError::IOError(
+                 format!("{}: {}", path.as_ref().display(), error_details(&e)))));
+        let file: ConfigFile = try!(
+            serde_json::from_str(&content)
+                .map_err(|e| ConfigError::ParseError(error_details(&e))));
+        let log_level = match file.loglevel {
+            Some(ref level) => try!(
+                log::LogLevelFilter::from_str(level)
+                    .map_err(|_| ConfigError::ParseError(
+                        format!("Unknown log level: {}", level)))),
+            None => self.log_level
+        };
+        Ok(Config{
+            consul: file.consul.unwrap_or(self.consul.clone()),
+            consul_token: file.consul_token.or(self.consul_token.clone()),
+            docker: file.docker.unwrap_or(self.docker.clone()),
+            log_level: log_level
+        })
+    }
+}
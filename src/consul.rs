@@ -2,6 +2,7 @@
 // ext libs
 use hyper;
 use hyper::{header};
+use serde_json;
 // traits
 use std::io::{Read};
 use std::fmt;
@@ -12,7 +13,10 @@ use std::ops::Deref;
 use std::sync::mpsc;
 use std::thread;
 // internal
+use config::Config;
 use human_uri::HumanURI;
+use spec;
+use metrics;
 use utils::*;
 
 
@@ -23,6 +27,16 @@ pub enum ConsulError {
     ProtocolError(String) // wrong data inside spec / headers
 }
 
+impl ConsulError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            &ConsulError::HTTPError(..) => "HTTPError",
+            &ConsulError::IOError(..) => "IOError",
+            &ConsulError::ProtocolError(..) => "ProtocolError"
+        }
+    }
+}
+
 impl error::Error for ConsulError {
     fn description(&self) -> &str {
         match self {
@@ -69,9 +83,69 @@ pub enum ConsulKeyResponse {
     Key(String, i64)
 }
 
+#[derive(Serialize, Debug, Clone)]
+pub struct ServiceCheck {
+    #[serde(rename="Script", skip_serializing_if_none)]
+    pub script: Option<String>,
+    #[serde(rename="HTTP", skip_serializing_if_none)]
+    pub http: Option<String>,
+    #[serde(rename="Interval")]
+    pub interval: String,
+    #[serde(rename="Timeout")]
+    pub timeout: String
+}
+
+/// Body of agent service registration request
+#[derive(Serialize, Debug, Clone)]
+pub struct ServiceRegistration {
+    // unique per container, as old and new deploys can be registered at once
+    #[serde(rename="ID", skip_serializing_if_none)]
+    pub id: Option<String>,
+    #[serde(rename="Name")]
+    pub name: String,
+    #[serde(rename="Tags")]
+    pub tags: Vec<String>,
+    #[serde(rename="Address", skip_serializing_if_none)]
+    pub address: Option<String>,
+    #[serde(rename="Port")]
+    pub port: u16,
+    #[serde(rename="Check")]
+    pub check: ServiceCheck
+}
+
+impl ServiceRegistration {
+    /// Builds registration of service, running at host of spec;
+    /// `bound_port` is host port docker published service port to, if known
+    pub fn from_service(spec: &spec::Spec, service: &spec::Service,
+                        bound_port: Option<u16>) -> Self {
+        let port = bound_port.or(service.host_port).unwrap_or(service.port);
+        let address = spec.host.clone().unwrap_or("127.0.0.1".to_string());
+        let (script, http) = match &service.check.method {
+            &spec::CheckMethod::Script(ref script) => (Some(script.clone()), None),
+            &spec::CheckMethod::Http(ref url) => (None, Some(url.clone())),
+            &spec::CheckMethod::HttpPath(ref path) =>
+                (None, Some(format!("http://{}:{}{}", address, port, path)))
+        };
+        ServiceRegistration{
+            id: None,
+            name: service.name.clone(),
+            tags: service.tags.clone(),
+            address: spec.host.clone(),
+            port: port,
+            check: ServiceCheck{
+                script: script,
+                http: http,
+                interval: format!("{}s", service.check.interval),
+                timeout: format!("{}s", service.check.timeout)
+            }
+        }
+    }
+}
+
 pub struct Consul {
     client: hyper::Client,
-    endpoint: HumanURI
+    endpoint: HumanURI,
+    token: Option<String>
 }
 
 impl Consul {
@@ -80,7 +154,22 @@ impl Consul {
         let endpoint = HumanURI::parse(raw_uri);
         Consul{
             client: hyper::Client::new(),
-            endpoint: endpoint
+            endpoint: endpoint,
+            token: None
+        }
+    }
+
+    pub fn from_config(config: &Config) -> Consul {
+        let mut consul = Consul::new(&config.consul);
+        consul.token = config.consul_token.clone();
+        consul
+    }
+
+    /// Adds ACL token to request url, if it was configured
+    fn authorize(&self, url: HumanURI) -> HumanURI {
+        match self.token {
+            Some(ref token) => url.add_query_params([("token", token)].iter()),
+            None => url
         }
     }
 
@@ -92,11 +181,12 @@ impl Consul {
             .with_query_params([("wait", "10s"), ("raw", "")].iter())
             .add_query_params([("index", index)].iter());
         debug!("Get {}...", url);
+        let url = self.authorize(url);
         let mut response = try!(self.client.get(url)
                                 .header(header::Connection::close())
                                 .send());
         let mut body = String::new();
-        response.read_to_string(&mut body).unwrap();
+        try!(response.read_to_string(&mut body).map_err(hyper::Error::from));
         if response.status != hyper::Ok {
             return Err(ConsulError::HTTPError(format!("{}", response.status),
                                               body));
@@ -111,14 +201,275 @@ impl Consul {
         }
     }
 
-    pub fn watch_key<T:AsRef<str> + Send>(self, key: T) -> mpsc::Receiver<String> {
+    /// Returns current value of key without waiting for changes
+    pub fn read_key<T:AsRef<str>>(&self, key: T) -> Result<String, ConsulError> {
+        match try!(self.get_key(key, 0)) {
+            ConsulKeyResponse::Key(value, _) => Ok(value),
+            ConsulKeyResponse::NoNewContent =>
+                Err(ConsulError::ProtocolError("No content received".to_owned()))
+        }
+    }
+
+    /// Returns name and address of node, agent is running on
+    pub fn agent_node(&self) -> Result<(String, String), ConsulError> {
+        let url = self.endpoint.with_path("/v1/agent/self");
+        debug!("Get {}...", url);
+        let url = self.authorize(url);
+        let mut response = try!(self.client.get(url)
+                                .header(header::Connection::close())
+                                .send());
+        let mut body = String::new();
+        try!(response.read_to_string(&mut body).map_err(hyper::Error::from));
+        if response.status != hyper::Ok {
+            return Err(ConsulError::HTTPError(format!("{}", response.status),
+                                              body));
+        }
+        let agent: serde_json::Value = try!(
+            serde_json::from_str(&body)
+                .map_err(|e| ConsulError::ProtocolError(error_details(&e))));
+        let field = |path: &str| agent.lookup(path)
+            .and_then(|v| v.as_string())
+            .map(|v| v.to_string())
+            .ok_or(ConsulError::ProtocolError(format!("No {} received", path)));
+        Ok((try!(field("Config.NodeName")), try!(field("Member.Addr"))))
+    }
+
+    /// Resolves discovery to value of its env var: "address:port" of
+    /// passing service instance, or comma separated list of them, when
+    /// discovery is multiple
+    pub fn discover(&self, discovery: &spec::Discovery) -> Result<String, ConsulError> {
+        let mut url = self.endpoint.with_path("/v1/health/service")
+            .add_path(&discovery.service)
+            .with_query_params([("passing", "")].iter());
+        if let Some(ref tag) = discovery.tag {
+            url = url.add_query_params([("tag", tag)].iter());
+        }
+        debug!("Get {}...", url);
+        let url = self.authorize(url);
+        let mut response = try!(self.client.get(url)
+                                .header(header::Connection::close())
+                                .send());
+        let mut body = String::new();
+        try!(response.read_to_string(&mut body).map_err(hyper::Error::from));
+        if response.status != hyper::Ok {
+            return Err(ConsulError::HTTPError(format!("{}", response.status),
+                                              body));
+        }
+        let entries: serde_json::Value = try!(
+            serde_json::from_str(&body)
+                .map_err(|e| ConsulError::ProtocolError(error_details(&e))));
+        let entries = match entries.as_array() {
+            Some(entries) => entries,
+            None => return Err(ConsulError::ProtocolError(
+                "Health entries should be array".to_owned()))
+        };
+        let mut addresses = vec!();
+        for entry in entries.iter() {
+            let service_address = entry.lookup("Service.Address")
+                .and_then(|a| a.as_string())
+                .unwrap_or("");
+            let address = if service_address.is_empty() {
+                entry.lookup("Node.Address").and_then(|a| a.as_string())
+            } else {
+                Some(service_address)
+            };
+            match (address, entry.lookup("Service.Port").and_then(|p| p.as_u64())) {
+                (Some(address), Some(port)) =>
+                    addresses.push(format!("{}:{}", address, port)),
+                _ => return Err(ConsulError::ProtocolError(
+                    "No address or port in health entry".to_owned()))
+            }
+        }
+        if addresses.is_empty() {
+            return Err(ConsulError::ProtocolError(
+                format!("No passing instances of {}", discovery.service)));
+        }
+        if discovery.multiple {
+            Ok(addresses.join(","))
+        } else {
+            Ok(addresses.swap_remove(0))
+        }
+    }
+
+    fn put(&self, url: HumanURI, body: &str) -> Result<String, ConsulError> {
+        debug!("Put {}...", url);
+        let url = self.authorize(url);
+        let mut response = try!(self.client.put(url)
+                                .header(header::Connection::close())
+                                .body(body)
+                                .send());
+        let mut body = String::new();
+        try!(response.read_to_string(&mut body).map_err(hyper::Error::from));
+        if response.status != hyper::Ok {
+            return Err(ConsulError::HTTPError(format!("{}", response.status),
+                                              body));
+        }
+        Ok(body)
+    }
+
+    pub fn put_key<T:AsRef<str>>(&self, key: T, value: &str) -> Result<(), ConsulError> {
+        let url = self.endpoint.with_path("/v1/kv")
+            .add_path(key);
+        self.put(url, value).map(|_| ())
+    }
+
+    pub fn register_service(&self, registration: &ServiceRegistration)
+                            -> Result<(), ConsulError>
+    {
+        let url = self.endpoint.with_path("/v1/agent/service/register");
+        let body = try!(serde_json::to_string(registration)
+                        .map_err(|e| ConsulError::ProtocolError(error_details(&e))));
+        self.put(url, &body).map(|_| ())
+    }
+
+    pub fn deregister_service(&self, id: &str) -> Result<(), ConsulError> {
+        let url = self.endpoint.with_path("/v1/agent/service/deregister")
+            .add_path(id);
+        self.put(url, "").map(|_| ())
+    }
+
+    /// Creates new session, which releases all locks held by it, when
+    /// it is not renewed during `ttl` seconds
+    pub fn create_session(&self, ttl: u64) -> Result<String, ConsulError> {
+        let url = self.endpoint.with_path("/v1/session/create");
+        let body = format!("{{\"Name\": \"condo\", \"TTL\": \"{}s\", \
+\"Behavior\": \"release\"}}", ttl);
+        let response = try!(self.put(url, &body));
+        let session: serde_json::Value = try!(
+            serde_json::from_str(&response)
+                .map_err(|e| ConsulError::ProtocolError(error_details(&e))));
+        match session.lookup("ID") {
+            Some(&serde_json::Value::String(ref id)) => Ok(id.clone()),
+            _ => Err(ConsulError::ProtocolError("No session ID received".to_owned()))
+        }
+    }
+
+    pub fn renew_session(&self, session: &str) -> Result<(), ConsulError> {
+        let url = self.endpoint.with_path("/v1/session/renew")
+            .add_path(session);
+        self.put(url, "").map(|_| ())
+    }
+
+    /// Invalidates session, releasing all locks held by it
+    pub fn destroy_session(&self, session: &str) -> Result<(), ConsulError> {
+        let url = self.endpoint.with_path("/v1/session/destroy")
+            .add_path(session);
+        self.put(url, "").map(|_| ())
+    }
+
+    /// Tries to lock key with session; returns true, when lock is held by
+    /// given session
+    pub fn acquire_key<T:AsRef<str>>(&self, key: T, session: &str)
+                                     -> Result<bool, ConsulError>
+    {
+        let url = self.endpoint.with_path("/v1/kv")
+            .add_path(key)
+            .with_query_params([("acquire", session)].iter());
+        let response = try!(self.put(url, ""));
+        match response.trim() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            other => Err(ConsulError::ProtocolError(
+                format!("Unexpected lock response: {}", other)))
+        }
+    }
+
+    /// Keeps trying to acquire lock on key and reports every change of
+    /// leadership: true, when lock was acquired, false when it was lost.
+    /// Config received from `reload` replaces consul client. When message
+    /// is received from `release`, session is destroyed and returned
+    /// receiver is closed.
+    pub fn hold_lock<T:AsRef<str> + Send>(self, key: T, ttl: u64,
+                                          reload: mpsc::Receiver<Config>,
+                                          release: mpsc::Receiver<()>)
+                                          -> mpsc::Receiver<bool>
+    {
+        let (tx, rx) = mpsc::channel();
+        let thread_key = key.as_ref().to_owned();
+        thread::spawn(move || {
+            let mut consul = self;
+            let mut leader = false;
+            'session: loop {
+                while let Ok(config) = reload.try_recv() {
+                    info!("Reloading lock of {} from {}", thread_key, config.consul);
+                    consul = Consul::from_config(&config);
+                }
+                if release.try_recv().is_ok() {
+                    break;
+                }
+                let session = match consul.create_session(ttl) {
+                    Ok(session) => session,
+                    Err(e) => {
+                        error!("Consul error:{}", e);
+                        sleep(5);
+                        continue;
+                    }
+                };
+                info!("Created consul session: {}", session);
+                loop {
+                    // session is kept: if it's unknown to reloaded consul,
+                    // renewal fails and new one is created
+                    while let Ok(config) = reload.try_recv() {
+                        info!("Reloading lock of {} from {}", thread_key, config.consul);
+                        consul = Consul::from_config(&config);
+                    }
+                    // on errors leadership is kept as is: lock is held by
+                    // session, until it's renewed or lost
+                    match consul.acquire_key(&thread_key, &session) {
+                        Ok(acquired) => if acquired != leader {
+                            leader = acquired;
+                            ignore_result!(tx.send(leader));
+                        },
+                        Err(e) => error!("Consul error:{}", e)
+                    }
+                    // wake up every second to release lock without delay
+                    for _ in 0..ttl / 3 {
+                        if release.try_recv().is_ok() {
+                            info!("Releasing lock of {}...", thread_key);
+                            if let Err(e) = consul.destroy_session(&session) {
+                                warn!("Can't destroy session {}: {}", session, e);
+                            }
+                            break 'session;
+                        }
+                        sleep(1);
+                    }
+                    if let Err(e) = consul.renew_session(&session) {
+                        warn!("Session {} was invalidated: {}", session, e);
+                        break;
+                    }
+                }
+                if leader {
+                    leader = false;
+                    ignore_result!(tx.send(leader));
+                }
+            }
+        });
+
+        rx
+    }
+
+    /// Watches for changes of key; when new config is received from
+    /// `reload`, reconnects using it and re-reads key from scratch
+    pub fn watch_key<T:AsRef<str> + Send>(self, key: T, reload: mpsc::Receiver<Config>)
+                                          -> mpsc::Receiver<String>
+    {
         let (tx, rx) = mpsc::channel();
         let thread_key = key.as_ref().to_owned();
         thread::spawn(move || {
+            let mut consul = self;
             let mut index = 0;
             loop {
-                match self.get_key(&thread_key, index) {
-                    Err(e) => error!("Consul error:{}", e),
+                if let Ok(config) = reload.try_recv() {
+                    info!("Reloading watch of {} from {}", thread_key, config.consul);
+                    consul = Consul::from_config(&config);
+                    index = 0;
+                }
+                match consul.get_key(&thread_key, index) {
+                    Err(e) => {
+                        error!("Consul error:{}", e);
+                        metrics::inc(&metrics::CONSUL_ERRORS,
+                                     &[("kind", e.kind())]);
+                    },
                     Ok(ConsulKeyResponse::NoNewContent) =>
                         debug!("No new content received..."),
                     Ok(ConsulKeyResponse::Key(spec, new_index)) => {
@@ -0,0 +1,107 @@
+// ext libs
+use serde_json;
+use serde_json::Value;
+// traits
+use std::fmt;
+// std
+use std::collections::BTreeMap;
+// internal
+use spec::Spec;
+
+/// Field-level change between two specs; path looks like `image.tag` or
+/// `envs[DATABASE_URL].value`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added(String, Value),
+    Removed(String, Value),
+    Changed(String, Value, Value)
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Change::Added(ref path, ref value) =>
+                write!(f, "+ {}: {}", path, value),
+            &Change::Removed(ref path, ref value) =>
+                write!(f, "- {}: {}", path, value),
+            &Change::Changed(ref path, ref old, ref new) =>
+                write!(f, "~ {}: {} -> {}", path, old, new)
+        }
+    }
+}
+
+/// Returns key, which identifies element of list: envs and services are
+/// identified by name, discoveries by env var, volumes by mount point
+fn element_key(value: &Value) -> Option<String> {
+    value.find("name").or(value.find("env")).or(value.find("to"))
+        .and_then(|key| key.as_string())
+        .map(|key| key.to_string())
+}
+
+/// Converts list to map by element keys, if all elements have them
+fn keyed(values: &[Value]) -> Option<BTreeMap<String, &Value>> {
+    let mut map = BTreeMap::new();
+    for value in values.iter() {
+        match element_key(value) {
+            Some(key) => { map.insert(key, value); },
+            None => return None
+        }
+    }
+    Some(map)
+}
+
+fn diff_maps(path: &str, old: BTreeMap<String, &Value>, new: BTreeMap<String, &Value>,
+             item_path: &Fn(&str, &str) -> String, changes: &mut Vec<Change>)
+{
+    for (key, old_value) in old.iter() {
+        let p = item_path(path, key);
+        match new.get(key) {
+            Some(new_value) => diff_values(&p, old_value, new_value, changes),
+            None => changes.push(Change::Removed(p, (*old_value).clone()))
+        }
+    }
+    for (key, new_value) in new.iter() {
+        if !old.contains_key(key) {
+            changes.push(Change::Added(item_path(path, key), (*new_value).clone()));
+        }
+    }
+}
+
+fn diff_values(path: &str, old: &Value, new: &Value, changes: &mut Vec<Change>) {
+    if old == new {
+        return;
+    }
+    match (old, new) {
+        (&Value::Object(ref old_map), &Value::Object(ref new_map)) => {
+            let field_path = |path: &str, key: &str| if path.is_empty() {
+                key.to_string()
+            } else {
+                format!("{}.{}", path, key)
+            };
+            diff_maps(path,
+                      old_map.iter().map(|(k, v)| (k.clone(), v)).collect(),
+                      new_map.iter().map(|(k, v)| (k.clone(), v)).collect(),
+                      &field_path, changes)
+        },
+        (&Value::Array(ref old_items), &Value::Array(ref new_items)) => {
+            match (keyed(old_items), keyed(new_items)) {
+                (Some(old_map), Some(new_map)) => {
+                    let item_path = |path: &str, key: &str| format!("{}[{}]", path, key);
+                    diff_maps(path, old_map, new_map, &item_path, changes)
+                },
+                // lists like cmd or tags are compared as a whole
+                _ => changes.push(Change::Changed(path.to_string(),
+                                                  old.clone(), new.clone()))
+            }
+        },
+        _ => changes.push(Change::Changed(path.to_string(), old.clone(), new.clone()))
+    }
+}
+
+/// Returns what should be changed to turn old spec into new one
+pub fn diff(old: &Spec, new: &Spec) -> Vec<Change> {
+    let mut changes = vec!();
+    diff_values("", &serde_json::to_value(old), &serde_json::to_value(new),
+                &mut changes);
+    changes
+}
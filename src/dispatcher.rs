@@ -1,26 +1,73 @@
 
 // ext libs
+use time;
 // traits
+use std::str::FromStr;
 // std
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::mpsc;
 use std::thread;
 use std::fmt;
+use std::cmp;
+use std::mem;
 // internal
 use spec::*;
 use event::*;
-use docker::*;
+use docker::{ContainerConfig, ContainerInfo, container_name, port_key};
+use runtime::ContainerRuntime;
+use consul::{Consul, ServiceRegistration};
+use interpolate::{Resolver, interpolate_spec, resolve_secrets, resolve_discoveries};
+use utils::*;
+use status::Status;
+use metrics;
+use diff;
+
+// how many received events to keep for status
+const RECENT_EVENTS: usize = 20;
+// how many last lines of failed container output to report
+const LOG_TAIL: usize = 20;
+// how many times crashed stable container is restarted within
+// CRASH_WINDOW seconds before giving up
+const MAX_RESTARTS: usize = 5;
+const CRASH_WINDOW: i64 = 600;
 
 #[derive(Clone, Debug)]
 struct Deploy {
-    spec: Spec
+    spec: Spec,
+    // id of container running the spec, once it was created
+    container: Option<String>,
+    // host ports of services, as bound by docker
+    ports: BTreeMap<String, u16>,
+    // ids of services registered in consul agent
+    services: Vec<String>
 }
 
 impl Deploy {
     fn new(spec: Spec) -> Self {
         Deploy{
-            spec: spec
+            spec: spec,
+            container: None,
+            ports: BTreeMap::new(),
+            services: vec!()
         }
     }
+
+    /// Checks whether deploy was made from the same spec, so applying
+    /// given spec would be no-op
+    fn runs(&self, spec: &Spec) -> bool {
+        self.spec == *spec
+    }
+}
+
+/// Host ports of spec services bound for container, by service name
+fn service_ports(spec: &Spec, info: &ContainerInfo) -> BTreeMap<String, u16> {
+    spec.services.iter()
+        .filter_map(|service| {
+            info.ports.get(&port_key(service))
+                .map(|&port| (service.name.clone(), port))
+        })
+        .collect()
 }
 
 #[derive(Clone,Debug)]
@@ -46,9 +93,47 @@ impl fmt::Display for State {
     }
 }
 
+/// What to do with running containers when condo is stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutdownPolicy {
+    Leave,
+    Stop
+}
+
+impl FromStr for ShutdownPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "leave" => Ok(ShutdownPolicy::Leave),
+            "stop" => Ok(ShutdownPolicy::Stop),
+            other => Err(format!("Unknown shutdown policy: {}", other))
+        }
+    }
+}
+
 pub struct Dispatcher {
     state: State,
-    docker: Docker,
+    // false, when dispatcher should wait for leadership before deploying
+    leader: bool,
+    // latest spec received while not being leader
+    pending: Option<Spec>,
+    shutdown_policy: ShutdownPolicy,
+    last_error: Option<String>,
+    last_failure: Option<FailureReason>,
+    last_diff: Vec<String>,
+    recent_events: VecDeque<String>,
+    // last crashed container and times of its crashes within CRASH_WINDOW
+    crashes: Option<(String, VecDeque<time::Tm>)>,
+    // replaced containers, which are stopped after `Stop::AfterTimeout`,
+    // with their kill timeouts
+    stopping: RefCell<BTreeMap<String, u16>>,
+    // when current state was entered
+    since: time::Tm,
+    runtime: Box<ContainerRuntime + Send>,
+    resolver: Box<Resolver + Send>,
+    // agent to register services of running deploys in
+    consul: Option<Consul>,
+    subscribers: Vec<mpsc::Sender<Status>>,
     send_events: mpsc::Sender<Event>,
     receive_events: mpsc::Receiver<Event>
 }
@@ -56,16 +141,57 @@ pub struct Dispatcher {
 
 impl Dispatcher {
     #[inline]
-    pub fn new(docker: Docker) -> Self {
+    pub fn new(runtime: Box<ContainerRuntime + Send>,
+               resolver: Box<Resolver + Send>) -> Self {
         let (send_events, receive_events) = mpsc::channel();
         Dispatcher{
             state: State::Start,
-            docker: docker,
+            leader: true,
+            pending: None,
+            shutdown_policy: ShutdownPolicy::Leave,
+            last_error: None,
+            last_failure: None,
+            last_diff: vec!(),
+            recent_events: VecDeque::new(),
+            crashes: None,
+            stopping: RefCell::new(BTreeMap::new()),
+            since: time::now_utc(),
+            runtime: runtime,
+            resolver: resolver,
+            consul: None,
+            subscribers: vec!(),
             send_events: send_events,
             receive_events: receive_events
         }
     }
 
+    /// Makes dispatcher hold received specs until it gets
+    /// `Event::LeadershipAcquired`
+    pub fn require_leadership(mut self) -> Self {
+        self.leader = false;
+        self
+    }
+
+    pub fn on_shutdown(mut self, policy: ShutdownPolicy) -> Self {
+        self.shutdown_policy = policy;
+        self
+    }
+
+    /// Makes dispatcher register services of running deploys in consul
+    /// agent and deregister them, when deploys are stopped
+    pub fn register_services(mut self, consul: Consul) -> Self {
+        self.consul = Some(consul);
+        self
+    }
+
+    /// Returns receiver, which gets dispatcher status after every
+    /// transition
+    pub fn subscribe(&mut self) -> mpsc::Receiver<Status> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
     pub fn start(self) -> (thread::JoinHandle<()>, mpsc::Sender<Event>) {
         let send_events = self.send_events.clone();
         let h = thread::spawn(move || self.listen_events());
@@ -73,91 +199,869 @@ impl Dispatcher {
     }
 
     fn listen_events(mut self) {
-        for event in self.receive_events.iter() {
-            debug!("Current state: {}, received event: {:?}", self.state, event);
-            self.state = match &self.state {
-                &State::Start => match event {
-                    Event::NewSpec(spec) => self.start_initial_deploy(spec),
-                    _ => panic!("Invalid event")
-                },
-                &State::RunningStable{ref current} => match event {
-                    Event::NewSpec(spec) => match &spec.stop {
-                        // move stop dispatch inside
-                        &Stop::Before =>
-                            self.stop_current_and_start_deploy(current, spec),
-                        &Stop::AfterTimeout(_) =>
-                            self.start_new_deploy(current, spec)
-                    },
-                    _ => panic!("Invalid event")
-                },
-                &State::WaitingForFirstStable{ref candidate} => match event {
-                    Event::NewSpec(_) => unimplemented!(),
-                    Event::DeployFailed => State::Start,
-                    Event::GotStable =>
-                        State::RunningStable{current: candidate.to_owned()}
-                },
-                &State::WaitingForNewStable{ref last_stable, ref candidate} => match event {
-                    Event::NewSpec(_) => unimplemented!(),
-                    Event::DeployFailed =>
-                        self.restore_last_stable(last_stable),
-                    Event::GotStable =>
-                        State::RunningStable{current: candidate.to_owned()}
+        loop {
+            let event = match self.receive_events.recv() {
+                Ok(event) => event,
+                Err(_) => break
+            };
+            if !self.handle(event) {
+                break;
+            }
+        }
+    }
+
+    /// Applies event; returns false, when dispatcher should stop
+    fn handle(&mut self, event: Event) -> bool {
+        debug!("Current state: {}, received event: {:?}", self.state, event);
+        if let Event::Shutdown = event {
+            info!("Shutting down in state {}, policy: {:?}",
+                  self.state, self.shutdown_policy);
+            if self.shutdown_policy == ShutdownPolicy::Stop {
+                self.state = self.stop_all();
+            }
+            self.notify_subscribers();
+            return false;
+        }
+        if let Event::Reload(ref config) = event {
+            info!("Switching docker endpoint to {}, consul to {}",
+                  config.docker, config.consul);
+            self.runtime.set_endpoint(&config.docker);
+            self.resolver.reload(config);
+            if self.consul.is_some() {
+                self.consul = Some(Consul::from_config(config));
+            }
+            return true;
+        }
+        if let Event::StopContainer(ref id, timeout) = event {
+            if self.stopping.borrow_mut().remove(id).is_some() {
+                self.stop_container(id, timeout);
+            } else {
+                debug!("Container {} is already stopped", id);
+            }
+            return true;
+        }
+        match event {
+            Event::DeployFailed(ref fingerprint, ref reason)
+                if self.is_candidate(fingerprint) => {
+                metrics::inc(&metrics::DEPLOYS, &[("result", "failed")]);
+                self.last_error = Some(format!("Deploy of {} failed: {}",
+                                               fingerprint, reason));
+                self.last_failure = Some(reason.clone());
+            },
+            Event::GotStable(ref fingerprint) if self.is_candidate(fingerprint) =>
+                metrics::inc(&metrics::DEPLOYS, &[("result", "succeeded")]),
+            Event::NewSpec(ref spec) => {
+                if let Some(latest) = self.latest_spec().or(self.pending.clone()) {
+                    let changes = diff::diff(&latest, spec);
+                    if !changes.is_empty() {
+                        self.last_diff = changes.iter()
+                            .map(|change| change.to_string())
+                            .collect();
+                        info!("Spec {} changes {}:\n{}", spec.fingerprint(),
+                              latest.fingerprint(), self.last_diff.join("\n"));
+                    }
+                }
+            },
+            _ => ()
+        }
+        if self.recent_events.len() == RECENT_EVENTS {
+            self.recent_events.pop_front();
+        }
+        self.recent_events.push_back(
+            format!("{} {}", time::now_utc().rfc3339(), event));
+        let previous = self.state.to_string();
+        self.state = match event {
+            Event::LeadershipAcquired => {
+                info!("Acquired leadership");
+                self.leader = true;
+                match self.pending.take() {
+                    Some(spec) => self.start_initial_deploy(spec),
+                    None => self.state.clone()
+                }
+            },
+            Event::LeadershipLost => {
+                warn!("Lost leadership, stopping deploys...");
+                self.leader = false;
+                self.pending = self.latest_spec();
+                self.stop_all()
+            },
+            Event::NewSpec(ref spec) if !self.leader => {
+                info!("Not a leader, holding spec {}...", spec.fingerprint());
+                self.pending = Some(spec.clone());
+                self.state.clone()
+            },
+            Event::ContainerDied(ref id, oom_killed) =>
+                self.handle_death(id, oom_killed),
+            Event::RestartContainer(ref id) => self.restart_container(id),
+            event => self.transition(event)
+        };
+        debug!("Transitioned to state: {}", self.state);
+        if self.state.to_string() != previous {
+            let now = time::now_utc();
+            let spent = (now - self.since).num_milliseconds() as f64 / 1000.0;
+            metrics::add(&metrics::STATE_SECONDS, &[("state", previous.as_str())], spent);
+            metrics::set(&metrics::STATE, &[("state", previous.as_str())], 0.0);
+            self.since = now;
+        }
+        metrics::set(&metrics::STATE, &[("state", self.state.to_string().as_str())], 1.0);
+        self.notify_subscribers();
+        true
+    }
+
+    fn status(&self) -> Status {
+        let (current, candidate) = match &self.state {
+            &State::Start => (None, None),
+            &State::RunningStable{ref current} => (Some(current), None),
+            &State::WaitingForFirstStable{ref candidate} => (None, Some(candidate)),
+            &State::WaitingForNewStable{ref last_stable, ref candidate} =>
+                (Some(last_stable), Some(candidate)),
+            &State::RunningStableWaitingForNew{ref current, ref candidate} =>
+                (Some(current), Some(candidate))
+        };
+        let containers = current.iter().chain(candidate.iter())
+            .filter_map(|deploy| deploy.container.clone())
+            .collect();
+        Status{
+            state: self.state.to_string(),
+            current: current.map(|deploy| deploy.spec.fingerprint()),
+            candidate: candidate.map(|deploy| deploy.spec.fingerprint()),
+            containers: containers,
+            ports: current.map_or(BTreeMap::new(), |deploy| deploy.ports.clone()),
+            spec: current.map(|deploy| deploy.spec.clone()),
+            last_error: self.last_error.clone(),
+            last_failure: self.last_failure.clone(),
+            last_diff: self.last_diff.clone(),
+            events: self.recent_events.iter().cloned().collect(),
+            since: format!("{}", self.since.rfc3339()),
+            updated_at: format!("{}", time::now_utc().rfc3339())
+        }
+    }
+
+    fn notify_subscribers(&self) {
+        let status = self.status();
+        for subscriber in self.subscribers.iter() {
+            ignore_result!(subscriber.send(status.clone()));
+        }
+    }
+
+    fn transition(&self, event: Event) -> State {
+        // promotion or failure of replaced or dropped candidate can
+        // still be queued
+        match &event {
+            &Event::GotStable(ref fingerprint) |
+            &Event::DeployFailed(ref fingerprint, _) if !self.is_candidate(fingerprint) =>
+                return self.ignore_event(&event),
+            _ => ()
+        }
+        match &self.state {
+            &State::Start => match event {
+                Event::NewSpec(spec) => self.start_initial_deploy(spec),
+                Event::Redeploy | Event::Rollback => self.ignore_event(&event),
+                _ => panic!("Invalid event")
+            },
+            &State::RunningStable{ref current} => match event {
+                Event::NewSpec(ref spec) if current.runs(spec) =>
+                    self.skip_spec(spec),
+                Event::NewSpec(spec) => self.replace_current(current, spec),
+                Event::Redeploy =>
+                    self.replace_current(current, current.spec.clone()),
+                Event::Rollback => self.ignore_event(&event),
+                _ => panic!("Invalid event")
+            },
+            &State::WaitingForFirstStable{ref candidate} => match event {
+                Event::NewSpec(ref spec) if candidate.runs(spec) =>
+                    self.skip_spec(spec),
+                Event::NewSpec(spec) => self.replace_first_candidate(candidate, spec),
+                Event::DeployFailed(..) => self.drop_candidate(candidate),
+                Event::GotStable(_) =>
+                    State::RunningStable{current: candidate.to_owned()},
+                Event::Redeploy | Event::Rollback => self.ignore_event(&event),
+                _ => panic!("Invalid event")
+            },
+            &State::WaitingForNewStable{ref last_stable, ref candidate} => match event {
+                Event::NewSpec(ref spec) if candidate.runs(spec) =>
+                    self.skip_spec(spec),
+                Event::NewSpec(spec) =>
+                    self.replace_new_candidate(last_stable, candidate, spec),
+                Event::DeployFailed(..) | Event::Rollback =>
+                    self.restore_last_stable(last_stable, candidate),
+                Event::GotStable(_) =>
+                    State::RunningStable{current: candidate.to_owned()},
+                Event::Redeploy => self.ignore_event(&event),
+                _ => panic!("Invalid event")
+            },
+            &State::RunningStableWaitingForNew{ref current, ref candidate} => match event {
+                Event::NewSpec(ref spec) if candidate.runs(spec) =>
+                    self.skip_spec(spec),
+                // rollout is reverted: current deploy already runs spec
+                Event::NewSpec(ref spec) if current.runs(spec) =>
+                    self.stop_candidate(current, candidate),
+                Event::NewSpec(spec) => {
+                    self.stop_deploy(candidate);
+                    self.replace_current(current, spec)
                 },
-                &State::RunningStableWaitingForNew{ref current, ref candidate} => match event {
-                    Event::NewSpec(_) => unimplemented!(),
-                    Event::DeployFailed =>
-                        State::RunningStable{current: current.to_owned()},
-                    Event::GotStable =>
-                        self.replace_old_stable(current, candidate)
+                Event::DeployFailed(..) =>
+                    self.stop_candidate(current, candidate),
+                Event::Rollback =>
+                    self.abort_candidate(current, candidate),
+                Event::GotStable(_) =>
+                    self.replace_old_stable(current, candidate),
+                Event::Redeploy => self.ignore_event(&event),
+                _ => panic!("Invalid event")
+            }
+        }
+    }
+
+    /// Checks whether spec with fingerprint is deployed by current candidate
+    fn is_candidate(&self, fingerprint: &str) -> bool {
+        match &self.state {
+            &State::WaitingForFirstStable{ref candidate} |
+            &State::WaitingForNewStable{ref candidate, ..} |
+            &State::RunningStableWaitingForNew{ref candidate, ..} =>
+                candidate.spec.fingerprint() == fingerprint,
+            _ => false
+        }
+    }
+
+    /// Returns deploy, which is considered running stable
+    fn current_mut(&mut self) -> Option<&mut Deploy> {
+        match &mut self.state {
+            &mut State::RunningStable{ref mut current} |
+            &mut State::RunningStableWaitingForNew{ref mut current, ..} => Some(current),
+            _ => None
+        }
+    }
+
+    /// Candidate, which dies, has failed; current deploy is restarted
+    /// with exponential backoff, until it crashes too often and is dropped
+    fn handle_death(&mut self, id: &str, oom_killed: bool) -> State {
+        let candidate = match &self.state {
+            &State::WaitingForFirstStable{ref candidate} |
+            &State::WaitingForNewStable{ref candidate, ..} |
+            &State::RunningStableWaitingForNew{ref candidate, ..}
+                if candidate.container.as_ref().map_or(false, |c| c == id) =>
+                Some(candidate.spec.fingerprint()),
+            _ => None
+        };
+        if let Some(fingerprint) = candidate {
+            let reason = self.exit_reason(id, oom_killed, None);
+            warn!("Candidate container {} died: {}", id, reason);
+            ignore_result!(self.send_events.send(Event::DeployFailed(fingerprint, reason)));
+            return self.state.clone();
+        }
+        let fingerprint = match self.current_mut() {
+            Some(ref current) if current.container.as_ref().map_or(false, |c| c == id) =>
+                current.spec.fingerprint(),
+            _ => {
+                debug!("Container {} is not running any deploy, ignoring its death", id);
+                return self.state.clone();
+            }
+        };
+        let reason = self.exit_reason(id, oom_killed, None);
+        self.count_crash(id, &fingerprint, reason)
+    }
+
+    /// Counts crash of current deploy's container; it is restarted with
+    /// backoff, or deploy is dropped, when it crashed too often
+    fn count_crash(&mut self, id: &str, fingerprint: &str, reason: FailureReason)
+                   -> State
+    {
+        let now = time::now_utc();
+        let mut crash_times = match self.crashes.take() {
+            Some((ref crashed, ref times)) if crashed == id => times.clone(),
+            _ => VecDeque::new()
+        };
+        while crash_times.front().map_or(false, |&at| (now - at).num_seconds() >= CRASH_WINDOW) {
+            crash_times.pop_front();
+        }
+        crash_times.push_back(now);
+        let crashes = crash_times.len();
+        self.crashes = Some((id.to_string(), crash_times));
+        let message = format!("Container {} of deploy {} crashed: {}", id, fingerprint,
+                              reason);
+        if crashes > MAX_RESTARTS {
+            error!("{}; it crashed {} times in {}s, giving up",
+                   message, crashes, CRASH_WINDOW);
+            metrics::inc(&metrics::DEPLOYS, &[("result", "failed")]);
+            self.last_error = Some(format!("{}; crashed {} times in {}s, not restarting",
+                                           message, crashes, CRASH_WINDOW));
+            self.last_failure = Some(reason);
+            return self.drop_current();
+        }
+        let delay = cmp::min(1u64 << (crashes - 1), 60);
+        warn!("{}; restarting in {}s ({}/{})", message, delay, crashes, MAX_RESTARTS);
+        self.last_error = Some(message);
+        let id = id.to_string();
+        let send_events = self.send_events.clone();
+        thread::spawn(move || {
+            sleep(delay);
+            ignore_result!(send_events.send(Event::RestartContainer(id)));
+        });
+        self.state.clone()
+    }
+
+    /// Removes crashed current deploy; waiting candidate, if any, becomes
+    /// first one
+    fn drop_current(&self) -> State {
+        match &self.state {
+            &State::RunningStable{ref current} => {
+                self.stop_deploy(current);
+                State::Start
+            },
+            &State::RunningStableWaitingForNew{ref current, ref candidate} => {
+                self.stop_deploy(current);
+                State::WaitingForFirstStable{candidate: candidate.to_owned()}
+            },
+            _ => self.state.clone()
+        }
+    }
+
+    /// Starts crashed container again, if it still runs current deploy
+    fn restart_container(&mut self, id: &str) -> State {
+        let template = match self.current_mut() {
+            Some(ref current) if current.container.as_ref().map_or(false, |c| c == id) =>
+                current.spec.clone(),
+            _ => {
+                debug!("Container {} was replaced, not restarting", id);
+                return self.state.clone();
+            }
+        };
+        info!("Restarting container {}...", id);
+        match self.start_container(id, &template.fingerprint()) {
+            Ok(info) => {
+                // dynamic ports are bound anew, so services are registered
+                // again with them
+                let ports = service_ports(&template, &info);
+                let services = match interpolate_spec(&template, &*self.resolver) {
+                    Ok(spec) => self.register(&spec, id, &ports),
+                    Err(e) => {
+                        warn!("Can't register services of container {}: {}", id, e);
+                        vec!()
+                    }
+                };
+                if let Some(current) = self.current_mut() {
+                    current.ports = ports;
+                    current.services = services;
                 }
-            };
-            debug!("Transitioned to state: {}", self.state);
+            },
+            // no death event follows failed start request, so it is
+            // counted as crash here
+            Err(reason @ FailureReason::Start(_)) => {
+                error!("Can't restart container {}: {}", id, reason);
+                return self.count_crash(id, &template.fingerprint(), reason);
+            },
+            Err(reason) => {
+                warn!("Restarted container {} stopped right away: {}", id, reason);
+                self.last_error = Some(format!("Can't restart container {}: {}",
+                                               id, reason));
+            }
         }
+        self.state.clone()
     }
 
-    fn start_initial_deploy(&self, init: Spec) -> State {
-        // RUN DEPLOY
-        match self.docker.pull_image(&init.image) {
-            Ok(_) => debug!("info: ok"),
-            Err(e) => warn!("lol: {}, {:?}", e, e)
+    /// Returns the newest spec dispatcher knows about
+    fn latest_spec(&self) -> Option<Spec> {
+        match &self.state {
+            &State::Start => None,
+            &State::RunningStable{ref current} => Some(current.spec.clone()),
+            &State::WaitingForFirstStable{ref candidate} |
+            &State::WaitingForNewStable{ref candidate, ..} |
+            &State::RunningStableWaitingForNew{ref candidate, ..} =>
+                Some(candidate.spec.clone())
+        }
+    }
+
+    fn stop_all(&self) -> State {
+        debug!("stop all deploys in state: {}", self.state);
+        let stopping = mem::replace(&mut *self.stopping.borrow_mut(), BTreeMap::new());
+        for (id, kill_timeout) in stopping.into_iter() {
+            self.stop_container(&id, kill_timeout);
+        }
+        match &self.state {
+            &State::Start => (),
+            &State::RunningStable{ref current} |
+            &State::WaitingForFirstStable{candidate: ref current} =>
+                self.stop_deploy(current),
+            &State::WaitingForNewStable{ref candidate, ..} =>
+                // last stable was already stopped
+                self.stop_deploy(candidate),
+            &State::RunningStableWaitingForNew{ref current, ref candidate} => {
+                self.stop_deploy(candidate);
+                self.stop_deploy(current);
+            }
+        };
+        State::Start
+    }
+
+    fn skip_spec(&self, spec: &Spec) -> State {
+        info!("Spec {} is already deployed, skipping...", spec.fingerprint());
+        self.state.clone()
+    }
+
+    fn ignore_event(&self, event: &Event) -> State {
+        warn!("Can't handle {} in state {}, ignoring...", event, self.state);
+        self.state.clone()
+    }
+
+    fn replace_current(&self, current: &Deploy, new: Spec) -> State {
+        match &new.stop {
+            &Stop::Before =>
+                self.stop_current_and_start_deploy(current, new),
+            &Stop::AfterTimeout(_) =>
+                self.start_new_deploy(current, new)
+        }
+    }
+
+    /// Pulls image, creates and starts container for spec; failures are
+    /// reported back to dispatcher as `Event::DeployFailed`. There are no
+    /// health checks yet, so deploy, which container is running, is
+    /// promoted right away with `Event::GotStable`
+    fn deploy(&self, spec: Spec) -> Deploy {
+        metrics::inc(&metrics::DEPLOYS, &[("result", "started")]);
+        let mut deploy = Deploy::new(spec);
+        match self.run_container(&deploy.spec) {
+            Ok((info, spec)) => {
+                info!("Deploy of {} is running in container {}",
+                      deploy.spec.fingerprint(), info.id);
+                deploy.ports = service_ports(&deploy.spec, &info);
+                deploy.services = self.register(&spec, &info.id, &deploy.ports);
+                deploy.container = Some(info.id);
+                ignore_result!(self.send_events.send(
+                    Event::GotStable(deploy.spec.fingerprint())));
+            },
+            Err(reason) => {
+                warn!("Deploy of {} failed: {}", deploy.spec.fingerprint(), reason);
+                ignore_result!(self.send_events.send(
+                    Event::DeployFailed(deploy.spec.fingerprint(), reason)));
+            }
+        }
+        deploy
+    }
+
+    /// Returns info of started container and interpolated spec
+    fn run_container(&self, template: &Spec)
+                     -> Result<(ContainerInfo, Spec), FailureReason>
+    {
+        let spec = try!(interpolate_spec(template, &*self.resolver)
+                        .map_err(FailureReason::Prepare));
+        try!(self.runtime.pull_image(&spec.image)
+             .map_err(|e| FailureReason::Pull(e.to_string())));
+        let secrets = try!(resolve_secrets(&spec, &*self.resolver)
+                           .map_err(FailureReason::Prepare));
+        let discovered = try!(resolve_discoveries(&spec, &*self.resolver)
+                              .map_err(|e| FailureReason::Prepare(
+                                  format!("can't resolve discovery {}", e))));
+        let config = ContainerConfig::from_spec(&spec, &template.fingerprint(),
+                                                &discovered, &secrets);
+        let name = container_name(&spec);
+        let id = try!(self.runtime.create_container(
+            name.as_ref().map(|n| n.as_str()), &config)
+                      .map_err(|e| FailureReason::Create(e.to_string())));
+        match self.start_container(&id, &template.fingerprint()) {
+            Ok(info) => Ok((info, spec)),
+            Err(reason) => {
+                self.remove_container(&id);
+                Err(reason)
+            }
+        }
+    }
+
+    fn start_container(&self, id: &str, deploy: &str)
+                       -> Result<ContainerInfo, FailureReason>
+    {
+        try!(self.runtime.start_container(id)
+             .map_err(|e| FailureReason::Start(e.to_string())));
+        self.follow_logs(id, deploy);
+        let info = try!(self.runtime.inspect_container(id)
+                        .map_err(|e| FailureReason::Start(e.to_string())));
+        if info.running {
+            Ok(info)
+        } else {
+            Err(self.exit_reason(id, info.oom_killed, Some(info.exit_code)))
+        }
+    }
+
+    /// Describes why container stopped; exit code is waited for, when
+    /// it's not known yet
+    fn exit_reason(&self, id: &str, oom_killed: bool, exit_code: Option<i64>)
+                   -> FailureReason
+    {
+        let output = self.log_tail(id);
+        if oom_killed {
+            return FailureReason::OomKilled(output);
+        }
+        let exit_code = exit_code.unwrap_or_else(|| {
+            self.runtime.wait_container(id).unwrap_or_else(|e| {
+                warn!("Can't get exit code of container {}: {}", id, e);
+                -1
+            })
+        });
+        FailureReason::Exited(exit_code, output)
+    }
+
+    /// Forwards container output to condo log, tagged with deploy
+    /// fingerprint and container id, until container stops
+    fn follow_logs(&self, id: &str, deploy: &str) {
+        let lines = match self.runtime.logs(id, true, None) {
+            Ok(lines) => lines,
+            Err(e) => {
+                warn!("Can't follow logs of container {}: {}", id, e);
+                return;
+            }
         };
-        let state = State::WaitingForFirstStable{
-            candidate: Deploy::new(init)
+        let tag = format!("{} {}", deploy, &id[..cmp::min(12, id.len())]);
+        thread::spawn(move || {
+            for line in lines {
+                if line.stderr {
+                    warn!("[{}] {}", tag, line.text);
+                } else {
+                    info!("[{}] {}", tag, line.text);
+                }
+            }
+            debug!("[{}] output finished", tag);
+        });
+    }
+
+    /// Returns last lines of container output to report with failure
+    fn log_tail(&self, id: &str) -> Vec<String> {
+        match self.runtime.logs(id, false, Some(LOG_TAIL)) {
+            Ok(lines) => lines.map(|line| line.text).collect(),
+            Err(e) => {
+                warn!("Can't get logs of container {}: {}", id, e);
+                vec!()
+            }
+        }
+    }
+
+    fn remove_container(&self, id: &str) {
+        if let Err(e) = self.runtime.remove_container(id) {
+            warn!("Can't remove container {}: {}", id, e);
+        }
+    }
+
+    fn stop_container(&self, id: &str, timeout: u16) {
+        info!("Stopping container {}...", id);
+        if let Err(e) = self.runtime.stop_container(id, timeout) {
+            warn!("Can't stop container {}: {}", id, e);
+        }
+        self.remove_container(id);
+    }
+
+    /// Registers services of spec, running in container, in consul
+    /// agent; returns ids of registered services
+    fn register(&self, spec: &Spec, container: &str, ports: &BTreeMap<String, u16>)
+                -> Vec<String>
+    {
+        let consul = match self.consul {
+            Some(ref consul) => consul,
+            None => return vec!()
         };
-        state
+        let mut registered = vec!();
+        for service in spec.services.iter() {
+            let mut registration = ServiceRegistration::from_service(
+                spec, service, ports.get(&service.name).cloned());
+            let id = format!("{}-{}", service.name,
+                             &container[..cmp::min(12, container.len())]);
+            registration.id = Some(id.clone());
+            match consul.register_service(&registration) {
+                Ok(_) => {
+                    info!("Registered service {} on port {}", id, registration.port);
+                    registered.push(id);
+                },
+                Err(e) => warn!("Can't register service {}: {}", id, e)
+            }
+        }
+        registered
+    }
+
+    fn deregister(&self, deploy: &Deploy) {
+        if let Some(ref consul) = self.consul {
+            for id in deploy.services.iter() {
+                if let Err(e) = consul.deregister_service(id) {
+                    warn!("Can't deregister service {}: {}", id, e);
+                }
+            }
+        }
+    }
+
+    fn stop_deploy(&self, deploy: &Deploy) {
+        self.deregister(deploy);
+        if let Some(ref id) = deploy.container {
+            self.stop_container(id, deploy.spec.kill_timeout.unwrap_or(10));
+        }
+    }
+
+    fn start_initial_deploy(&self, init: Spec) -> State {
+        State::WaitingForFirstStable{
+            candidate: self.deploy(init)
+        }
     }
 
     fn stop_current_and_start_deploy(&self, current: &Deploy, new: Spec) -> State {
-        // STOP CURRENT (TODO: actually stop just before new strt)
-        let state = State::WaitingForNewStable{
-            candidate: Deploy::new(new),
+        self.stop_deploy(current);
+        State::WaitingForNewStable{
+            candidate: self.deploy(new),
             last_stable: current.to_owned()
-        };
-        // RUN DEPLOY
-        state
+        }
     }
 
     fn start_new_deploy(&self, current: &Deploy, new: Spec) -> State {
-        let state = State::RunningStableWaitingForNew{
+        State::RunningStableWaitingForNew{
             current: current.to_owned(),
-            candidate: Deploy::new(new)
-        };
-        // RUN DEPLOY OF candaidate
-        state
+            candidate: self.deploy(new)
+        }
     }
 
-    fn restore_last_stable(&self, last_stable: &Deploy) -> State {
-        let state = State::WaitingForFirstStable{
-            candidate: last_stable.to_owned()
-        };
-        // RUN DEPLOY
-        state
+    fn replace_first_candidate(&self, candidate: &Deploy, new: Spec) -> State {
+        self.stop_deploy(candidate);
+        self.start_initial_deploy(new)
+    }
+
+    /// Last stable deploy was already stopped, it's kept to restore it,
+    /// if new candidate fails too
+    fn replace_new_candidate(&self, last_stable: &Deploy, candidate: &Deploy,
+                             new: Spec) -> State {
+        self.stop_deploy(candidate);
+        State::WaitingForNewStable{
+            candidate: self.deploy(new),
+            last_stable: last_stable.to_owned()
+        }
+    }
+
+    fn drop_candidate(&self, candidate: &Deploy) -> State {
+        self.stop_deploy(candidate);
+        State::Start
+    }
+
+    fn restore_last_stable(&self, last_stable: &Deploy, candidate: &Deploy) -> State {
+        metrics::inc(&metrics::ROLLBACKS, &[]);
+        self.stop_deploy(candidate);
+        State::WaitingForFirstStable{
+            candidate: self.deploy(last_stable.spec.clone())
+        }
+    }
+
+    fn stop_candidate(&self, current: &Deploy, candidate: &Deploy) -> State {
+        self.stop_deploy(candidate);
+        State::RunningStable{current: current.to_owned()}
+    }
+
+    fn abort_candidate(&self, current: &Deploy, candidate: &Deploy) -> State {
+        metrics::inc(&metrics::ROLLBACKS, &[]);
+        self.stop_candidate(current, candidate)
     }
 
     fn replace_old_stable(&self, current: &Deploy, new: &Deploy) -> State {
-        // Schedule to stop old
-        debug!("schedule to stop old: {:?}", current);
-        let state = State::RunningStable{current: new.to_owned()};
-        state
+        if let (&Stop::AfterTimeout(timeout), Some(id)) = (&new.spec.stop,
+                                                            current.container.clone()) {
+            // Schedule to stop old; it's deregistered right away, so
+            // only new one gets traffic
+            debug!("schedule to stop old in {}s: {:?}", timeout, current);
+            self.deregister(current);
+            let kill_timeout = current.spec.kill_timeout.unwrap_or(10);
+            self.stopping.borrow_mut().insert(id.clone(), kill_timeout);
+            let send_events = self.send_events.clone();
+            thread::spawn(move || {
+                sleep(timeout as u64);
+                ignore_result!(send_events.send(Event::StopContainer(id, kill_timeout)));
+            });
+        } else {
+            self.stop_deploy(current);
+        }
+        State::RunningStable{current: new.to_owned()}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use consul::Consul;
+    use event::{Event, FailureReason};
+    use interpolate::HostResolver;
+    use runtime::Fake;
+    use spec::{Spec, Stop};
+    use super::{Dispatcher, ShutdownPolicy};
+
+    fn spec(tag: &str, stop: Stop) -> Spec {
+        let mut spec = Spec::parse(r#"{"image": {"name": "app", "tag": "v1"},
+"cmd": [], "services": [], "envs": [], "discoveries": [], "kill_timeout": null}"#,
+                                   None).unwrap();
+        spec.image.tag = tag.to_string();
+        spec.stop = stop;
+        spec
+    }
+
+    // specs have no placeholders, secrets or discoveries, and services
+    // aren't registered, so consul is never queried
+    fn dispatcher(fake: &Fake) -> Dispatcher {
+        let resolver = HostResolver::new(Consul::new("127.0.0.1:1"));
+        Dispatcher::new(Box::new(fake.clone()), Box::new(resolver))
+    }
+
+    /// Handles events, dispatcher has sent to itself
+    fn drain(dispatcher: &mut Dispatcher) {
+        while let Ok(event) = dispatcher.receive_events.try_recv() {
+            dispatcher.handle(event);
+        }
+    }
+
+    fn send(dispatcher: &mut Dispatcher, event: Event) {
+        dispatcher.handle(event);
+        drain(dispatcher);
+    }
+
+    fn containers(fake: &Fake) -> Vec<(String, String)> {
+        fake.containers().into_iter()
+            .map(|(id, container)| (id, container.config.image))
+            .collect()
+    }
+
+    #[test]
+    fn first_deploy_becomes_stable() {
+        let fake = Fake::new();
+        let mut dispatcher = dispatcher(&fake);
+        let v1 = spec("v1", Stop::Before);
+        send(&mut dispatcher, Event::NewSpec(v1.clone()));
+        assert_eq!(dispatcher.state.to_string(), "RunningStable");
+        assert_eq!(dispatcher.status().current, Some(v1.fingerprint()));
+        let running = fake.containers();
+        assert_eq!(running.len(), 1);
+        let container = running.values().next().unwrap();
+        assert_eq!(container.name, None);
+        assert_eq!(container.config.image, "app:v1");
+        assert!(container.info.running);
+    }
+
+    #[test]
+    fn failed_first_deploy_returns_to_start() {
+        for operation in ["pull_image", "create_container", "start_container"].iter() {
+            let fake = Fake::new();
+            fake.fail(operation);
+            let mut dispatcher = dispatcher(&fake);
+            send(&mut dispatcher, Event::NewSpec(spec("v1", Stop::Before)));
+            assert_eq!(dispatcher.state.to_string(), "Start", "{} failed", operation);
+            assert!(fake.containers().is_empty(), "{} failed", operation);
+            assert!(dispatcher.status().last_failure.is_some());
+        }
+    }
+
+    #[test]
+    fn failed_deploy_with_stop_before_restores_last_stable() {
+        let fake = Fake::new();
+        let mut dispatcher = dispatcher(&fake);
+        let v1 = spec("v1", Stop::Before);
+        send(&mut dispatcher, Event::NewSpec(v1.clone()));
+        fake.fail("start_container");
+        dispatcher.handle(Event::NewSpec(spec("v2", Stop::Before)));
+        assert_eq!(dispatcher.state.to_string(), "WaitingForNewStable");
+        fake.recover("start_container");
+        drain(&mut dispatcher);
+        assert_eq!(dispatcher.state.to_string(), "RunningStable");
+        assert_eq!(dispatcher.status().current, Some(v1.fingerprint()));
+        let running = containers(&fake);
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0].1, "app:v1");
+    }
+
+    #[test]
+    fn replacing_with_stop_after_timeout_schedules_stop() {
+        let fake = Fake::new();
+        let mut dispatcher = dispatcher(&fake);
+        send(&mut dispatcher, Event::NewSpec(spec("v1", Stop::AfterTimeout(1))));
+        let old = dispatcher.status().containers[0].clone();
+        let v2 = spec("v2", Stop::AfterTimeout(1));
+        send(&mut dispatcher, Event::NewSpec(v2.clone()));
+        assert_eq!(dispatcher.state.to_string(), "RunningStable");
+        assert_eq!(dispatcher.status().current, Some(v2.fingerprint()));
+        // old container keeps running until timeout
+        assert_eq!(containers(&fake).len(), 2);
+        match dispatcher.receive_events.recv().unwrap() {
+            Event::StopContainer(id, kill_timeout) => {
+                assert_eq!(id, old);
+                assert_eq!(kill_timeout, 10);
+            },
+            other => panic!("unexpected event {}", other)
+        }
+    }
+
+    #[test]
+    fn reverted_spec_keeps_current_container() {
+        let fake = Fake::new();
+        let mut dispatcher = dispatcher(&fake);
+        let v1 = spec("v1", Stop::AfterTimeout(1));
+        send(&mut dispatcher, Event::NewSpec(v1.clone()));
+        let current = dispatcher.status().containers[0].clone();
+        // promotion of candidate stays queued
+        dispatcher.handle(Event::NewSpec(spec("v2", Stop::AfterTimeout(1))));
+        send(&mut dispatcher, Event::NewSpec(v1.clone()));
+        assert_eq!(dispatcher.state.to_string(), "RunningStable");
+        assert_eq!(dispatcher.status().current, Some(v1.fingerprint()));
+        assert_eq!(dispatcher.status().containers, vec!(current));
+        assert_eq!(containers(&fake).len(), 1);
+    }
+
+    #[test]
+    fn shutdown_stops_replaced_containers() {
+        let fake = Fake::new();
+        let mut dispatcher = dispatcher(&fake).on_shutdown(ShutdownPolicy::Stop);
+        send(&mut dispatcher, Event::NewSpec(spec("v1", Stop::AfterTimeout(1))));
+        send(&mut dispatcher, Event::NewSpec(spec("v2", Stop::AfterTimeout(1))));
+        assert_eq!(containers(&fake).len(), 2);
+        assert!(!dispatcher.handle(Event::Shutdown));
+        assert!(fake.containers().is_empty());
+    }
+
+    #[test]
+    fn lost_leadership_stops_candidate_and_current() {
+        let fake = Fake::new();
+        let mut dispatcher = dispatcher(&fake);
+        send(&mut dispatcher, Event::NewSpec(spec("v1", Stop::AfterTimeout(1))));
+        // promotion of candidate stays queued
+        dispatcher.handle(Event::NewSpec(spec("v2", Stop::AfterTimeout(1))));
+        assert_eq!(dispatcher.state.to_string(), "RunningStableWaitingForNew");
+        let ids = dispatcher.status().containers;
+        assert_eq!(ids.len(), 2);
+        send(&mut dispatcher, Event::LeadershipLost);
+        assert_eq!(dispatcher.state.to_string(), "Start");
+        assert!(fake.containers().is_empty());
+        let operations = fake.operations();
+        for id in ids.iter() {
+            assert!(operations.contains(&format!("stop_container {}", id)));
+        }
+    }
+
+    #[test]
+    fn crash_looping_deploy_is_dropped() {
+        let fake = Fake::new();
+        let mut dispatcher = dispatcher(&fake);
+        send(&mut dispatcher, Event::NewSpec(spec("v1", Stop::Before)));
+        let id = dispatcher.status().containers[0].clone();
+        fake.print(&id, true, "boom");
+        fake.exit(&id, 3, false);
+        // restarts are scheduled with backoff, they don't arrive in time
+        for _ in 0..5 {
+            dispatcher.handle(Event::ContainerDied(id.clone(), false));
+            assert_eq!(dispatcher.state.to_string(), "RunningStable");
+        }
+        dispatcher.handle(Event::ContainerDied(id.clone(), false));
+        assert_eq!(dispatcher.state.to_string(), "Start");
+        assert!(fake.containers().is_empty());
+        assert_eq!(dispatcher.status().last_failure,
+                   Some(FailureReason::Exited(3, vec!("boom".to_string()))));
+    }
+
+    #[test]
+    fn failed_restarts_count_as_crashes() {
+        let fake = Fake::new();
+        let mut dispatcher = dispatcher(&fake);
+        send(&mut dispatcher, Event::NewSpec(spec("v1", Stop::Before)));
+        let id = dispatcher.status().containers[0].clone();
+        fake.fail("start_container");
+        for _ in 0..5 {
+            dispatcher.handle(Event::RestartContainer(id.clone()));
+            assert_eq!(dispatcher.state.to_string(), "RunningStable");
+        }
+        dispatcher.handle(Event::RestartContainer(id.clone()));
+        assert_eq!(dispatcher.state.to_string(), "Start");
+        assert!(fake.containers().is_empty());
     }
 }
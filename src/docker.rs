@@ -2,18 +2,32 @@
 // ext libs
 use hyper;
 use hyper::{header};
+use hyper::client::Response;
+use hyper::status::StatusCode;
 use serde_json;
 use serde_json::JSONStream;
+use time;
 // traits
+use std::io;
 use std::io::{Read};
 use std::fmt;
 use std::error;
 use std::convert::From;
+// std
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::sync::mpsc;
+use std::thread;
 // internal
+use config::Config;
 use human_uri::HumanURI;
+use metrics;
 use utils::*;
 use spec;
 
+/// Label, which marks containers started by condo; its value is
+/// fingerprint of deploy spec
+pub const DEPLOY_LABEL: &'static str = "condo.deploy";
+
 #[derive(Debug)]
 pub enum DockerError {
     HTTPError(String, String), // invalid status, etc
@@ -22,6 +36,17 @@ pub enum DockerError {
     RequestError(String), // error processing request: invalid params, etc
 }
 
+impl DockerError {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            &DockerError::HTTPError(..) => "HTTPError",
+            &DockerError::IOError(..) => "IOError",
+            &DockerError::ProtocolError(..) => "ProtocolError",
+            &DockerError::RequestError(..) => "RequestError"
+        }
+    }
+}
+
 impl error::Error for DockerError {
     fn description(&self) -> &str {
         match self {
@@ -75,6 +100,334 @@ impl From<serde_json::error::Error> for DockerError {
 }
 
 
+#[derive(Serialize, Debug, Clone)]
+pub struct PortBinding {
+    // empty string asks docker to pick free port
+    #[serde(rename="HostPort")]
+    pub host_port: String
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LogConfig {
+    #[serde(rename="Type")]
+    pub log_type: String,
+    #[serde(rename="Config")]
+    pub config: BTreeMap<String, String>
+}
+
+impl LogConfig {
+    /// Converts spec log settings; non-string config values should be
+    /// rejected by spec validation, here they are skipped
+    pub fn from_spec(log: &spec::Log) -> Self {
+        let config = log.config.as_ref()
+            .and_then(|config| config.as_object())
+            .map_or(BTreeMap::new(), |config| {
+                config.iter()
+                    .filter_map(|(k, v)| v.as_string().map(|v| (k.clone(), v.to_string())))
+                    .collect()
+            });
+        LogConfig{
+            log_type: log.log_type.clone(),
+            config: config
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct HostConfig {
+    #[serde(rename="Privileged")]
+    pub privileged: bool,
+    #[serde(rename="NetworkMode", skip_serializing_if_none)]
+    pub network_mode: Option<String>,
+    #[serde(rename="Binds", skip_serializing_if_empty)]
+    pub binds: Vec<String>,
+    #[serde(rename="PortBindings", skip_serializing_if_empty)]
+    pub port_bindings: BTreeMap<String, Vec<PortBinding>>,
+    #[serde(rename="LogConfig", skip_serializing_if_none)]
+    pub log_config: Option<LogConfig>
+}
+
+/// Body of container create request
+#[derive(Serialize, Debug, Clone)]
+pub struct ContainerConfig {
+    #[serde(rename="Image")]
+    pub image: String,
+    #[serde(rename="Cmd", skip_serializing_if_empty)]
+    pub cmd: Vec<String>,
+    #[serde(rename="Env")]
+    pub env: Vec<String>,
+    #[serde(rename="User", skip_serializing_if_none)]
+    pub user: Option<String>,
+    #[serde(rename="Labels", skip_serializing_if_empty)]
+    pub labels: BTreeMap<String, String>,
+    // values are always empty objects
+    #[serde(rename="ExposedPorts", skip_serializing_if_empty)]
+    pub exposed_ports: BTreeMap<String, BTreeMap<String, String>>,
+    #[serde(rename="HostConfig")]
+    pub host_config: HostConfig,
+    // names of env vars with secret values
+    #[serde(skip_serializing)]
+    pub secret_envs: Vec<String>
+}
+
+impl ContainerConfig {
+    /// Builds create request for interpolated spec; `deploy` is fingerprint
+    /// of spec template, container is labeled with, `discovered` are env
+    /// vars resolved from spec discoveries, `secrets` are resolved secret
+    /// env vars
+    pub fn from_spec(spec: &spec::Spec, deploy: &str, discovered: &[(String, String)],
+                     secrets: &[(String, String)]) -> Self {
+        let envs = spec.envs.iter()
+            .filter(|e| e.secret.is_none())
+            .map(|e| (&e.name, &e.value));
+        let discovered = discovered.iter().chain(secrets.iter())
+            .map(|&(ref name, ref value)| (name, value));
+        // with host network container ports are host ports already
+        let publish = spec.network_mode.as_ref().map_or(true, |mode| mode != "host");
+        let mut port_bindings = BTreeMap::new();
+        if publish {
+            for service in spec.services.iter() {
+                let host_port = service.host_port
+                    .map_or(String::new(), |port| port.to_string());
+                port_bindings.insert(port_key(service),
+                                     vec!(PortBinding{host_port: host_port}));
+            }
+        }
+        ContainerConfig{
+            image: image_name(&spec.image),
+            cmd: spec.cmd.clone(),
+            env: envs.chain(discovered)
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect(),
+            user: spec.user.clone(),
+            labels: vec!((DEPLOY_LABEL.to_string(), deploy.to_string()))
+                .into_iter().collect(),
+            exposed_ports: port_bindings.keys()
+                .map(|key| (key.clone(), BTreeMap::new()))
+                .collect(),
+            host_config: HostConfig{
+                privileged: spec.privileged,
+                network_mode: spec.network_mode.clone(),
+                binds: spec.volumes.iter().map(bind).collect(),
+                port_bindings: port_bindings,
+                log_config: spec.log.as_ref().map(LogConfig::from_spec)
+            },
+            secret_envs: secrets.iter().map(|&(ref name, _)| name.clone()).collect()
+        }
+    }
+
+    /// Returns copy with secret env values replaced, suitable for logging
+    pub fn redacted(&self) -> ContainerConfig {
+        let mut config = self.clone();
+        for env in config.env.iter_mut() {
+            let is_secret = env.splitn(2, '=').next()
+                .map_or(false, |name| self.secret_envs.iter().any(|s| s == name));
+            if is_secret {
+                *env = format!("{}=<redacted>", env.splitn(2, '=').next().unwrap());
+            }
+        }
+        config
+    }
+}
+
+/// Docker bind string: `from:to[:ro]`; docker itself treats `from`
+/// without '/' as named volume
+fn bind(volume: &spec::Volume) -> String {
+    if volume.read_only {
+        format!("{}:{}:ro", volume.from, volume.to)
+    } else {
+        format!("{}:{}", volume.from, volume.to)
+    }
+}
+
+/// Docker port key of service, e.g. `8080/tcp`
+pub fn port_key(service: &spec::Service) -> String {
+    format!("{}/{}", service.port, if service.udp { "udp" } else { "tcp" })
+}
+
+pub fn image_name(image: &spec::Image) -> String {
+    format!("{}:{}", &image.name, &image.tag)
+}
+
+/// Name of new container for spec; old and new containers can run at
+/// the same time, so names get creation timestamp to be unique
+pub fn container_name(spec: &spec::Spec) -> Option<String> {
+    spec.name.as_ref().map(|name| {
+        format!("{}-{}", name, time::now_utc().to_timespec().sec)
+    })
+}
+
+/// Query params of image pull request
+pub fn pull_params(image: &spec::Image) -> Vec<(&'static str, String)> {
+    vec!(("fromImage", image.name.clone()),
+         ("tag", image.tag.clone()))
+}
+
+/// Part of container inspect result, condo cares about
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerInfo {
+    pub id: String,
+    pub running: bool,
+    pub exit_code: i64,
+    pub oom_killed: bool,
+    /// host ports bound to container ports, by port key
+    pub ports: BTreeMap<String, u16>
+}
+
+/// Returns response, if it has one of expected statuses, or error with its
+/// body otherwise
+fn expect_status(mut response: Response, expected: &[StatusCode])
+                 -> Result<Response, DockerError>
+{
+    if expected.contains(&response.status) {
+        return Ok(response);
+    }
+    let mut body = String::new();
+    try!(response.read_to_string(&mut body).map_err(hyper::Error::from));
+    Err(DockerError::HTTPError(format!("{}", response.status), body))
+}
+
+/// Docker reports unknown or misconfigured log driver only in response
+/// body, e.g. "logger: no log driver named 'foo' is registered", so it is
+/// turned into error, which tells what's wrong
+fn log_driver_error(err: DockerError, config: &ContainerConfig) -> DockerError {
+    match (err, &config.host_config.log_config) {
+        (DockerError::HTTPError(status, body), &Some(ref log)) =>
+            if body.contains("log driver") || body.contains("logger") {
+                DockerError::RequestError(
+                    format!("log driver {:?} rejected by docker ({}): {}",
+                            log.log_type, status, body.trim()))
+            } else {
+                DockerError::HTTPError(status, body)
+            },
+        (err, _) => err
+    }
+}
+
+/// Reads host ports from `NetworkSettings.Ports` of inspect result, which
+/// looks like `{"8080/tcp": [{"HostIp": "0.0.0.0", "HostPort": "32768"}]}`;
+/// unpublished ports have null bindings
+fn bound_ports(value: &serde_json::Value) -> BTreeMap<String, u16> {
+    let mut ports = BTreeMap::new();
+    if let Some(map) = value.lookup("NetworkSettings.Ports").and_then(|v| v.as_object()) {
+        for (key, bindings) in map.iter() {
+            let host_port = bindings.as_array()
+                .and_then(|bindings| bindings.first())
+                .and_then(|binding| binding.find("HostPort"))
+                .and_then(|port| port.as_string())
+                .and_then(|port| port.parse().ok());
+            if let Some(port) = host_port {
+                ports.insert(key.clone(), port);
+            }
+        }
+    }
+    ports
+}
+
+fn string_field(value: &serde_json::Value, path: &str) -> Result<String, DockerError> {
+    match value.lookup(path).and_then(|v| v.as_string()) {
+        Some(s) => Ok(s.to_string()),
+        None => Err(DockerError::ProtocolError(
+            None, format!("No string {} value found", path)))
+    }
+}
+
+/// Line of container output
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogLine {
+    pub stderr: bool,
+    pub text: String
+}
+
+/// Splits multiplexed logs stream into lines. Every frame of stream has
+/// 8 byte header: stream type (1 is stdout, 2 is stderr), 3 zero bytes and
+/// big-endian u32 payload size. Frames don't respect line boundaries, so
+/// unfinished lines are kept until next frame of the same stream.
+pub struct LogLines<R: Read> {
+    reader: R,
+    lines: VecDeque<LogLine>,
+    // unfinished stdout and stderr lines
+    partial: [Vec<u8>; 2],
+    finished: bool
+}
+
+impl<R: Read> LogLines<R> {
+    pub fn new(reader: R) -> Self {
+        LogLines{
+            reader: reader,
+            lines: VecDeque::new(),
+            partial: [vec!(), vec!()],
+            finished: false
+        }
+    }
+
+    fn read_frame(&mut self) -> io::Result<()> {
+        let mut header = [0u8; 8];
+        try!(self.reader.read_exact(&mut header));
+        let size = (header[4] as usize) << 24 | (header[5] as usize) << 16 |
+                   (header[6] as usize) << 8 | header[7] as usize;
+        // payload of truncated frame is still split into lines
+        let mut payload = Vec::with_capacity(size);
+        try!((&mut self.reader).take(size as u64).read_to_end(&mut payload));
+        let truncated = payload.len() < size;
+        let stderr = header[0] == 2;
+        let partial = &mut self.partial[if stderr { 1 } else { 0 }];
+        for byte in payload.into_iter() {
+            if byte == b'\n' {
+                self.lines.push_back(LogLine{
+                    stderr: stderr,
+                    text: String::from_utf8_lossy(partial).into_owned()
+                });
+                partial.clear();
+            } else {
+                partial.push(byte);
+            }
+        }
+        if truncated {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof,
+                                      "truncated logs frame"));
+        }
+        Ok(())
+    }
+
+    /// Emits unfinished lines, when stream ends
+    fn flush(&mut self) {
+        for (i, partial) in self.partial.iter_mut().enumerate() {
+            if !partial.is_empty() {
+                self.lines.push_back(LogLine{
+                    stderr: i == 1,
+                    text: String::from_utf8_lossy(partial).into_owned()
+                });
+                partial.clear();
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for LogLines<R> {
+    type Item = LogLine;
+
+    fn next(&mut self) -> Option<LogLine> {
+        while self.lines.is_empty() && !self.finished {
+            if let Err(e) = self.read_frame() {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    debug!("Error while reading container logs: {}", e);
+                }
+                self.finished = true;
+                self.flush();
+            }
+        }
+        self.lines.pop_front()
+    }
+}
+
+/// Condo container, which stopped by itself
+#[derive(Debug, Clone, PartialEq)]
+pub struct ContainerDeath {
+    pub id: String,
+    pub oom_killed: bool
+}
+
 pub struct Docker {
     client: hyper::Client,
     endpoint: HumanURI
@@ -91,18 +444,40 @@ impl Docker {
         }
     }
 
+    pub fn set_endpoint(&mut self, raw_uri: &str) {
+        self.endpoint = HumanURI::parse(raw_uri);
+    }
+
+    /// Runs docker operation, recording its duration and errors
+    fn measure<T, F>(&self, operation: &str, f: F) -> Result<T, DockerError>
+        where F: FnOnce() -> Result<T, DockerError>
+    {
+        let started = time::precise_time_s();
+        let result = f();
+        metrics::observe(&metrics::DOCKER_REQUESTS, &[("operation", operation)],
+                         time::precise_time_s() - started);
+        if let Err(ref e) = result {
+            metrics::inc(&metrics::DOCKER_ERRORS,
+                         &[("operation", operation), ("kind", e.kind())]);
+        }
+        result
+    }
+
     pub fn pull_image(&self, image: &spec::Image) -> Result<(), DockerError> {
+        self.measure("pull_image", || self.do_pull_image(image))
+    }
+
+    fn do_pull_image(&self, image: &spec::Image) -> Result<(), DockerError> {
         // TODO: ADD X-Registry-Auth header
         let url = self.endpoint.with_path("/images/create")
-            .with_query_params([("fromImage", &image.name),
-                                ("tag", &image.tag)].iter());
+            .with_query_params(pull_params(image).iter());
         debug!("POST {}...", url);
         let mut response = try!(self.client.post(url)
                                 .header(header::Connection::close())
                             .send());
         if response.status != hyper::Ok {
             let mut body = String::new();
-            response.read_to_string(&mut body).unwrap();
+            try!(response.read_to_string(&mut body).map_err(hyper::Error::from));
             return Err(DockerError::HTTPError(format!("{}", response.status),
                                               body));
         }
@@ -125,10 +500,203 @@ impl Docker {
         return Ok(());
     }
 
+    pub fn create_container(&self, name: Option<&str>, config: &ContainerConfig)
+                            -> Result<String, DockerError>
+    {
+        self.measure("create_container", || {
+            let mut url = self.endpoint.with_path("/containers/create");
+            if let Some(name) = name {
+                url = url.with_query_params([("name", name)].iter());
+            }
+            let body = try!(serde_json::to_string(config));
+            debug!("POST {}...", url);
+            let response = try!(self.client.post(url)
+                                .header(header::Connection::close())
+                                .header(header::ContentType(
+                                    "application/json".parse().unwrap()))
+                                .body(&body[..])
+                                .send());
+            let response = try!(expect_status(response, &[StatusCode::Created])
+                                .map_err(|e| log_driver_error(e, config)));
+            let result:serde_json::Value = try!(serde_json::from_reader(response));
+            string_field(&result, "Id")
+        })
+    }
+
+    pub fn start_container(&self, id: &str) -> Result<(), DockerError> {
+        self.measure("start_container", || {
+            let url = self.endpoint.with_path("/containers")
+                .add_path(id)
+                .add_path("start");
+            debug!("POST {}...", url);
+            let response = try!(self.client.post(url)
+                                .header(header::Connection::close())
+                                .send());
+            // 304 means container is already started
+            try!(expect_status(response, &[StatusCode::NoContent,
+                                           StatusCode::NotModified]));
+            Ok(())
+        })
+    }
+
+    pub fn stop_container(&self, id: &str, timeout: u16) -> Result<(), DockerError> {
+        self.measure("stop_container", || {
+            let url = self.endpoint.with_path("/containers")
+                .add_path(id)
+                .add_path("stop")
+                .with_query_params([("t", timeout)].iter());
+            debug!("POST {}...", url);
+            let response = try!(self.client.post(url)
+                                .header(header::Connection::close())
+                                .send());
+            // 304 means container is already stopped
+            try!(expect_status(response, &[StatusCode::NoContent,
+                                           StatusCode::NotModified]));
+            Ok(())
+        })
+    }
+
+    pub fn remove_container(&self, id: &str) -> Result<(), DockerError> {
+        self.measure("remove_container", || {
+            let url = self.endpoint.with_path("/containers")
+                .add_path(id)
+                .with_query_params([("v", "1"), ("force", "1")].iter());
+            debug!("DELETE {}...", url);
+            let response = try!(self.client.delete(url)
+                                .header(header::Connection::close())
+                                .send());
+            try!(expect_status(response, &[StatusCode::NoContent]));
+            Ok(())
+        })
+    }
+
+    pub fn inspect_container(&self, id: &str) -> Result<ContainerInfo, DockerError> {
+        self.measure("inspect_container", || {
+            let url = self.endpoint.with_path("/containers")
+                .add_path(id)
+                .add_path("json");
+            debug!("GET {}...", url);
+            let response = try!(self.client.get(url)
+                                .header(header::Connection::close())
+                                .send());
+            let response = try!(expect_status(response, &[StatusCode::Ok]));
+            let result:serde_json::Value = try!(serde_json::from_reader(response));
+            let state = |path: &str| result.lookup(path).ok_or(
+                DockerError::ProtocolError(None, format!("No {} value found", path)));
+            Ok(ContainerInfo{
+                id: try!(string_field(&result, "Id")),
+                running: try!(state("State.Running")).as_boolean().unwrap_or(false),
+                exit_code: try!(state("State.ExitCode")).as_i64().unwrap_or(0),
+                oom_killed: try!(state("State.OOMKilled")).as_boolean().unwrap_or(false),
+                ports: bound_ports(&result)
+            })
+        })
+    }
+
+    pub fn wait_container(&self, id: &str) -> Result<i64, DockerError> {
+        self.measure("wait_container", || {
+            let url = self.endpoint.with_path("/containers")
+                .add_path(id)
+                .add_path("wait");
+            debug!("POST {}...", url);
+            let response = try!(self.client.post(url)
+                                .header(header::Connection::close())
+                                .send());
+            let response = try!(expect_status(response, &[StatusCode::Ok]));
+            let result:serde_json::Value = try!(serde_json::from_reader(response));
+            result.lookup("StatusCode").and_then(|code| code.as_i64()).ok_or(
+                DockerError::ProtocolError(None, "No StatusCode value found".to_string()))
+        })
+    }
+
+    /// Returns output of container; with `follow` stream ends only when
+    /// container stops. `tail` limits number of returned recent lines.
+    pub fn logs(&self, id: &str, follow: bool, tail: Option<usize>)
+                -> Result<LogLines<Response>, DockerError>
+    {
+        self.measure("logs", || {
+            let tail = tail.map_or("all".to_string(), |n| n.to_string());
+            let url = self.endpoint.with_path("/containers")
+                .add_path(id)
+                .add_path("logs")
+                .with_query_params([("stdout", "1"),
+                                    ("stderr", "1"),
+                                    ("follow", if follow { "1" } else { "0" }),
+                                    ("tail", &tail)].iter());
+            debug!("GET {}...", url);
+            let response = try!(self.client.get(url)
+                                .header(header::Connection::close())
+                                .send());
+            let response = try!(expect_status(response, &[StatusCode::Ok]));
+            Ok(LogLines::new(response))
+        })
+    }
+
+    /// Reads `die` and `oom` events of condo containers from docker events
+    /// stream, until it is closed
+    fn read_events(&self, deaths: &mpsc::Sender<ContainerDeath>)
+                   -> Result<(), DockerError>
+    {
+        let filters = format!("{{\"type\":[\"container\"],\"event\":[\"die\",\"oom\"],\
+\"label\":[\"{}\"]}}", DEPLOY_LABEL);
+        let url = self.endpoint.with_path("/events")
+            .with_query_params([("filters", filters)].iter());
+        debug!("GET {}...", url);
+        let response = try!(self.client.get(url)
+                            .header(header::Connection::close())
+                            .send());
+        let response = try!(expect_status(response, &[StatusCode::Ok]));
+        // docker sends oom right before die of the same container
+        let mut oom_killed = HashSet::new();
+        let events:JSONStream<serde_json::Value, _> = JSONStream::new(response.bytes());
+        for event in events {
+            let event = try!(event);
+            let id = try!(string_field(&event, "id"));
+            match try!(string_field(&event, "status")).as_str() {
+                "oom" => {
+                    oom_killed.insert(id);
+                },
+                "die" => {
+                    let oom = oom_killed.remove(&id);
+                    ignore_result!(deaths.send(ContainerDeath{id: id, oom_killed: oom}));
+                },
+                other => debug!("Ignoring docker event {} of {}", other, id)
+            }
+        }
+        Ok(())
+    }
+
+    /// Watches for condo containers, which stopped by themselves;
+    /// reconnects when stream breaks, new config is applied on reconnect
+    pub fn watch_events(self, reload: mpsc::Receiver<Config>)
+                        -> mpsc::Receiver<ContainerDeath>
+    {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut docker = self;
+            loop {
+                if let Ok(config) = reload.try_recv() {
+                    info!("Reloading docker events watch from {}", config.docker);
+                    docker.set_endpoint(&config.docker);
+                }
+                match docker.read_events(&tx) {
+                    Ok(_) => debug!("Docker events stream closed, reconnecting..."),
+                    Err(e) => {
+                        error!("Can't read docker events: {}", e);
+                        metrics::inc(&metrics::DOCKER_ERRORS,
+                                     &[("operation", "events"), ("kind", e.kind())]);
+                    }
+                }
+                sleep(5);
+            }
+        });
+        rx
+    }
+
     fn receive_image_id(&self, image: &spec::Image) -> Result<String, DockerError> {
         let url = self.endpoint
             .with_path("images")
-            .add_path(format!("{}:{}", &image.name, &image.tag))
+            .add_path(image_name(image))
             .add_path("json");
         debug!("GET {}...", url);
         let mut response = try!(self.client.get(url)
@@ -136,7 +704,7 @@ impl Docker {
                             .send());
         if response.status != hyper::Ok {
             let mut body = String::new();
-            response.read_to_string(&mut body).unwrap();
+            try!(response.read_to_string(&mut body).map_err(hyper::Error::from));
             return Err(DockerError::HTTPError(format!("{}", response.status),
                                               body));
         }
@@ -153,3 +721,62 @@ impl Docker {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use super::{LogLine, LogLines};
+
+    fn frame(stream: u8, payload: &str) -> Vec<u8> {
+        let size = payload.len();
+        let mut frame = vec!(stream, 0, 0, 0,
+                             (size >> 24) as u8, (size >> 16) as u8,
+                             (size >> 8) as u8, size as u8);
+        frame.extend(payload.bytes());
+        frame
+    }
+
+    fn lines(frames: Vec<Vec<u8>>) -> Vec<LogLine> {
+        let stream = frames.into_iter().fold(vec!(), |mut stream, frame| {
+            stream.extend(frame);
+            stream
+        });
+        LogLines::new(Cursor::new(stream)).collect()
+    }
+
+    fn line(stderr: bool, text: &str) -> LogLine {
+        LogLine{stderr: stderr, text: text.to_string()}
+    }
+
+    #[test]
+    fn joins_lines_split_between_frames() {
+        assert_eq!(lines(vec!(frame(1, "hel"), frame(1, "lo\nwor"),
+                              frame(1, "ld\n"))),
+                   vec!(line(false, "hello"), line(false, "world")));
+    }
+
+    #[test]
+    fn keeps_stdout_and_stderr_apart() {
+        assert_eq!(lines(vec!(frame(1, "out "), frame(2, "err"),
+                              frame(1, "line\n"), frame(2, "or\nlast"))),
+                   vec!(line(false, "out line"), line(true, "error"),
+                        line(true, "last")));
+    }
+
+    #[test]
+    fn emits_truncated_final_frame() {
+        let mut truncated = frame(2, "boom\npanic");
+        truncated.truncate(8 + 7);
+        assert_eq!(lines(vec!(frame(1, "started\n"), truncated)),
+                   vec!(line(false, "started"), line(true, "boom"),
+                        line(true, "pa")));
+    }
+
+    #[test]
+    fn ignores_truncated_header() {
+        let mut truncated = frame(1, "lost\n");
+        truncated.truncate(5);
+        assert_eq!(lines(vec!(frame(1, "ok\n"), truncated)),
+                   vec!(line(false, "ok")));
+    }
+}
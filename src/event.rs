@@ -1,12 +1,135 @@
-
+// ext libs
+use serde::{Serialize, Serializer};
+use serde_json::Value;
+// traits
+use std::fmt;
+// std
+use std::collections::BTreeMap;
 // internal
 use spec::*;
+use config::Config;
+
+/// Why deploy failed; exit reasons carry last lines of container output
+#[derive(Clone, Debug, PartialEq)]
+pub enum FailureReason {
+    // interpolation or secret resolution failed
+    Prepare(String),
+    Pull(String),
+    Create(String),
+    Start(String),
+    Exited(i64, Vec<String>),
+    OomKilled(Vec<String>),
+    // health checks are not implemented yet
+    #[allow(dead_code)]
+    HealthCheckTimeout
+}
+
+impl FailureReason {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            &FailureReason::Prepare(_) => "prepare",
+            &FailureReason::Pull(_) => "pull",
+            &FailureReason::Create(_) => "create",
+            &FailureReason::Start(_) => "start",
+            &FailureReason::Exited(..) => "exited",
+            &FailureReason::OomKilled(_) => "oom_killed",
+            &FailureReason::HealthCheckTimeout => "health_check_timeout"
+        }
+    }
+
+    fn output(&self) -> Option<&Vec<String>> {
+        match self {
+            &FailureReason::Exited(_, ref output) |
+            &FailureReason::OomKilled(ref output) => Some(output),
+            _ => None
+        }
+    }
+
+    /// Describes reason without container output
+    fn message(&self) -> String {
+        match self {
+            &FailureReason::Prepare(ref e) => format!("can't prepare spec: {}", e),
+            &FailureReason::Pull(ref e) => format!("can't pull image: {}", e),
+            &FailureReason::Create(ref e) => format!("can't create container: {}", e),
+            &FailureReason::Start(ref e) => format!("can't start container: {}", e),
+            &FailureReason::Exited(code, _) => format!("container exited with code {}", code),
+            &FailureReason::OomKilled(_) => "container was OOM killed".to_string(),
+            &FailureReason::HealthCheckTimeout => "health check timed out".to_string()
+        }
+    }
+}
+
+impl fmt::Display for FailureReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(write!(f, "{}", self.message()));
+        match self.output() {
+            Some(output) if !output.is_empty() =>
+                write!(f, "; last output:\n{}", output.join("\n")),
+            _ => Ok(())
+        }
+    }
+}
+
+// {"kind": "exited", "message": "...", "exit_code": 1, "output": [...]}
+impl Serialize for FailureReason {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer
+    {
+        let mut map = BTreeMap::new();
+        map.insert("kind", Value::String(self.kind().to_string()));
+        map.insert("message", Value::String(self.message()));
+        if let &FailureReason::Exited(code, _) = self {
+            map.insert("exit_code", Value::I64(code));
+        }
+        if let Some(output) = self.output() {
+            map.insert("output", Value::Array(
+                output.iter().map(|line| Value::String(line.clone())).collect()));
+        }
+        map.serialize(serializer)
+    }
+}
 
 // TODO: change to Rc<Spec>
 #[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub enum Event {
     NewSpec(Spec),
-    DeployFailed,
-    GotStable
+    // candidate failed: its spec fingerprint, reason
+    DeployFailed(String, FailureReason),
+    // candidate is considered stable: its spec fingerprint
+    GotStable(String),
+    LeadershipAcquired,
+    LeadershipLost,
+    // manual requests, received through api
+    Redeploy,
+    Rollback,
+    Shutdown,
+    Reload(Config),
+    // stop and remove old container: id, kill timeout
+    StopContainer(String, u16),
+    // container stopped by itself: id, whether it was OOM killed
+    ContainerDied(String, bool),
+    // start crashed container again after backoff: id
+    RestartContainer(String)
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Event::NewSpec(ref spec) => write!(f, "NewSpec({})", spec.fingerprint()),
+            &Event::DeployFailed(ref fingerprint, ref reason) =>
+                write!(f, "DeployFailed({}, {})", fingerprint, reason.kind()),
+            &Event::GotStable(ref fingerprint) => write!(f, "GotStable({})", fingerprint),
+            &Event::LeadershipAcquired => write!(f, "LeadershipAcquired"),
+            &Event::LeadershipLost => write!(f, "LeadershipLost"),
+            &Event::Redeploy => write!(f, "Redeploy"),
+            &Event::Rollback => write!(f, "Rollback"),
+            &Event::Shutdown => write!(f, "Shutdown"),
+            &Event::Reload(_) => write!(f, "Reload"),
+            &Event::StopContainer(ref id, _) => write!(f, "StopContainer({})", id),
+            &Event::ContainerDied(ref id, oom_killed) =>
+                write!(f, "ContainerDied({}{})", id, if oom_killed { ", OOM" } else { "" }),
+            &Event::RestartContainer(ref id) => write!(f, "RestartContainer({})", id)
+        }
+    }
 }
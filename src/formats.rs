@@ -0,0 +1,87 @@
+// ext libs
+use serde_json::Value;
+use toml;
+use yaml_rust::{Yaml, YamlLoader};
+// std
+use std::collections::BTreeMap;
+
+fn integer(i: i64) -> Value {
+    if i >= 0 { Value::U64(i as u64) } else { Value::I64(i) }
+}
+
+fn yaml_key(key: &Yaml) -> Result<String, String> {
+    match key {
+        &Yaml::String(ref s) | &Yaml::Real(ref s) => Ok(s.clone()),
+        &Yaml::Integer(i) => Ok(i.to_string()),
+        &Yaml::Boolean(b) => Ok(b.to_string()),
+        other => Err(format!("Unsupported yaml key: {:?}", other))
+    }
+}
+
+fn yaml_value(yaml: &Yaml) -> Result<Value, String> {
+    Ok(match yaml {
+        &Yaml::Real(ref s) => match s.parse() {
+            Ok(f) => Value::F64(f),
+            Err(_) => return Err(format!("Invalid yaml number: {}", s))
+        },
+        &Yaml::Integer(i) => integer(i),
+        &Yaml::String(ref s) => Value::String(s.clone()),
+        &Yaml::Boolean(b) => Value::Bool(b),
+        &Yaml::Array(ref items) => {
+            let mut values = vec!();
+            for item in items.iter() {
+                values.push(try!(yaml_value(item)));
+            }
+            Value::Array(values)
+        },
+        &Yaml::Hash(ref hash) => {
+            let mut map = BTreeMap::new();
+            for (k, v) in hash.iter() {
+                map.insert(try!(yaml_key(k)), try!(yaml_value(v)));
+            }
+            Value::Object(map)
+        },
+        &Yaml::Null => Value::Null,
+        other => return Err(format!("Unsupported yaml value: {:?}", other))
+    })
+}
+
+/// Parses single yaml document into json value
+pub fn yaml_to_json(s: &str) -> Result<Value, String> {
+    let docs = try!(YamlLoader::load_from_str(s).map_err(|e| format!("{:?}", e)));
+    match docs.len() {
+        1 => yaml_value(&docs[0]),
+        n => Err(format!("Expected one yaml document, found {}", n))
+    }
+}
+
+fn toml_value(value: &toml::Value) -> Value {
+    match value {
+        &toml::Value::String(ref s) => Value::String(s.clone()),
+        &toml::Value::Integer(i) => integer(i),
+        &toml::Value::Float(f) => Value::F64(f),
+        &toml::Value::Boolean(b) => Value::Bool(b),
+        &toml::Value::Datetime(ref s) => Value::String(s.clone()),
+        &toml::Value::Array(ref items) =>
+            Value::Array(items.iter().map(toml_value).collect()),
+        &toml::Value::Table(ref table) =>
+            Value::Object(table.iter()
+                          .map(|(k, v)| (k.clone(), toml_value(v)))
+                          .collect())
+    }
+}
+
+/// Parses toml document into json value
+pub fn toml_to_json(s: &str) -> Result<Value, String> {
+    let mut parser = toml::Parser::new(s);
+    match parser.parse() {
+        Some(table) => Ok(toml_value(&toml::Value::Table(table))),
+        None => {
+            let errors = parser.errors.iter().map(|e| {
+                let (line, col) = parser.to_linecol(e.lo);
+                format!("{}:{}: {}", line + 1, col + 1, e.desc)
+            }).collect::<Vec<_>>();
+            Err(errors.join("; "))
+        }
+    }
+}
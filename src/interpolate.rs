@@ -0,0 +1,268 @@
+// traits
+use std::str::FromStr;
+// std
+use std::cell::RefCell;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+// internal
+use config::Config;
+use consul::Consul;
+use spec::{Spec, Secret, Discovery};
+
+/// Host-specific value, which can be used in spec strings as `${...}`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Placeholder {
+    HostIp, // ${HOST_IP}: address of consul node
+    NodeName, // ${NODE_NAME}: name of consul node
+    Env(String), // ${env:VAR}: env var of condo process
+    Consul(String) // ${consul:key/path}: value of consul key
+}
+
+impl FromStr for Placeholder {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "HOST_IP" => Ok(Placeholder::HostIp),
+            "NODE_NAME" => Ok(Placeholder::NodeName),
+            _ if s.starts_with("env:") && s.len() > 4 =>
+                Ok(Placeholder::Env(s[4..].to_string())),
+            _ if s.starts_with("consul:") && s.len() > 7 =>
+                Ok(Placeholder::Consul(s[7..].to_string())),
+            _ => Err(format!("unknown placeholder ${{{}}}", s))
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder)
+}
+
+/// Splits template into literals and placeholders; `$${` is literal `${`
+fn parse(template: &str) -> Result<Vec<Part>, String> {
+    let mut parts = vec!();
+    let mut literal = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("${") {
+        if start > 0 && rest[..start].ends_with('$') {
+            literal.push_str(&rest[..start - 1]);
+            literal.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        literal.push_str(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => return Err(format!("unterminated placeholder in {:?}", template))
+        };
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal.clone()));
+            literal.clear();
+        }
+        parts.push(Part::Placeholder(try!(rest[start + 2..end].parse())));
+        rest = &rest[end + 1..];
+    }
+    literal.push_str(rest);
+    if !literal.is_empty() {
+        parts.push(Part::Literal(literal));
+    }
+    Ok(parts)
+}
+
+/// Checks that all placeholders in template are well-formed and known
+pub fn check(template: &str) -> Result<(), String> {
+    parse(template).map(|_| ())
+}
+
+/// Checks whether template has placeholders to interpolate
+pub fn has_placeholders(template: &str) -> Result<bool, String> {
+    parse(template).map(|parts| parts.iter().any(|part| match part {
+        &Part::Placeholder(_) => true,
+        &Part::Literal(_) => false
+    }))
+}
+
+pub trait Resolver {
+    fn resolve(&self, placeholder: &Placeholder) -> Result<String, String>;
+    fn secret(&self, secret: &Secret) -> Result<String, String>;
+    /// Returns env var value for discovery
+    fn discover(&self, discovery: &Discovery) -> Result<String, String>;
+    /// Switches to reloaded consul endpoint and token
+    fn reload(&mut self, config: &Config);
+}
+
+pub fn interpolate(template: &str, resolver: &Resolver) -> Result<String, String> {
+    let mut result = String::new();
+    for part in try!(parse(template)).into_iter() {
+        match part {
+            Part::Literal(s) => result.push_str(&s),
+            Part::Placeholder(p) => result.push_str(&try!(resolver.resolve(&p)))
+        }
+    }
+    Ok(result)
+}
+
+/// Returns spec with placeholders in env values, cmd, host and volume
+/// sources replaced
+pub fn interpolate_spec(spec: &Spec, resolver: &Resolver) -> Result<Spec, String> {
+    let mut spec = spec.clone();
+    for env in spec.envs.iter_mut() {
+        env.value = try!(interpolate(&env.value, resolver)
+                         .map_err(|e| format!("envs[{}]: {}", env.name, e)));
+    }
+    for (i, arg) in spec.cmd.iter_mut().enumerate() {
+        *arg = try!(interpolate(arg, resolver)
+                    .map_err(|e| format!("cmd[{}]: {}", i, e)));
+    }
+    for (i, volume) in spec.volumes.iter_mut().enumerate() {
+        volume.from = try!(interpolate(&volume.from, resolver)
+                           .map_err(|e| format!("volumes[{}].from: {}", i, e)));
+        try!(volume.check_from()
+             .map_err(|e| format!("volumes[{}].from: {}", i, e)));
+    }
+    if let Some(host) = spec.host.take() {
+        spec.host = Some(try!(interpolate(&host, resolver)
+                              .map_err(|e| format!("host: {}", e))));
+    }
+    Ok(spec)
+}
+
+/// Returns (name, value) of env vars, referencing secrets; values should
+/// only go to docker, never to logs
+pub fn resolve_secrets(spec: &Spec, resolver: &Resolver)
+                       -> Result<Vec<(String, String)>, String>
+{
+    let mut resolved = vec!();
+    for env in spec.envs.iter() {
+        if let Some(ref secret) = env.secret {
+            let value = try!(resolver.secret(secret)
+                             .map_err(|e| format!("envs[{}]: {}", env.name, e)));
+            resolved.push((env.name.clone(), value));
+        }
+    }
+    Ok(resolved)
+}
+
+/// Returns (env var, value) of spec discoveries
+pub fn resolve_discoveries(spec: &Spec, resolver: &Resolver)
+                           -> Result<Vec<(String, String)>, String>
+{
+    let mut discovered = vec!();
+    for discovery in spec.discoveries.iter() {
+        let value = try!(resolver.discover(discovery)
+                         .map_err(|e| format!("{}: {}", discovery.env, e)));
+        discovered.push((discovery.env.clone(), value));
+    }
+    Ok(discovered)
+}
+
+fn read_file(path: &str) -> Result<String, String> {
+    let mut content = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut content))
+         .map_err(|e| format!("secret file {}: {}", path, e)));
+    // files usually end with newline, which is not part of secret
+    let len = content.trim_right_matches('\n').len();
+    content.truncate(len);
+    Ok(content)
+}
+
+/// Resolves placeholders from condo environment and local consul agent
+pub struct HostResolver {
+    consul: Consul,
+    // (node name, address) of agent, fetched once
+    node: RefCell<Option<(String, String)>>
+}
+
+impl HostResolver {
+    pub fn new(consul: Consul) -> Self {
+        HostResolver{
+            consul: consul,
+            node: RefCell::new(None)
+        }
+    }
+
+    fn node(&self) -> Result<(String, String), String> {
+        if let Some(ref node) = *self.node.borrow() {
+            return Ok(node.clone());
+        }
+        let node = try!(self.consul.agent_node()
+                        .map_err(|e| format!("Can't get consul node info: {}", e)));
+        *self.node.borrow_mut() = Some(node.clone());
+        Ok(node)
+    }
+}
+
+impl Resolver for HostResolver {
+    fn resolve(&self, placeholder: &Placeholder) -> Result<String, String> {
+        match placeholder {
+            &Placeholder::HostIp => self.node().map(|(_, address)| address),
+            &Placeholder::NodeName => self.node().map(|(name, _)| name),
+            &Placeholder::Env(ref var) =>
+                env::var(var).map_err(|e| format!("env var {}: {}", var, e)),
+            &Placeholder::Consul(ref key) =>
+                self.consul.read_key(key)
+                .map_err(|e| format!("consul key {}: {}", key, e))
+        }
+    }
+
+    fn secret(&self, secret: &Secret) -> Result<String, String> {
+        match (&secret.consul, &secret.file) {
+            (&Some(ref key), _) => self.consul.read_key(key)
+                .map_err(|e| format!("secret consul key {}: {}", key, e)),
+            (&None, &Some(ref path)) => read_file(path),
+            (&None, &None) => Err("secret has neither consul nor file".to_string())
+        }
+    }
+
+    fn discover(&self, discovery: &Discovery) -> Result<String, String> {
+        self.consul.discover(discovery)
+            .map_err(|e| format!("service {}: {}", discovery.service, e))
+    }
+
+    // new endpoint can be different agent, so node info is fetched again
+    fn reload(&mut self, config: &Config) {
+        self.consul = Consul::from_config(config);
+        *self.node.borrow_mut() = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Part, Placeholder};
+
+    fn literal(s: &str) -> Part {
+        Part::Literal(s.to_string())
+    }
+
+    #[test]
+    fn parses_placeholders_between_literals() {
+        assert_eq!(parse("http://${HOST_IP}:${env:PORT}/").unwrap(),
+                   vec!(literal("http://"), Part::Placeholder(Placeholder::HostIp),
+                        literal(":"),
+                        Part::Placeholder(Placeholder::Env("PORT".to_string())),
+                        literal("/")));
+    }
+
+    #[test]
+    fn escapes_placeholder_with_double_dollar() {
+        assert_eq!(parse("$${HOST_IP}").unwrap(), vec!(literal("${HOST_IP}")));
+        assert_eq!(parse("a$${b} ${NODE_NAME}").unwrap(),
+                   vec!(literal("a${b} "), Part::Placeholder(Placeholder::NodeName)));
+        // escaped placeholder doesn't need closing brace
+        assert_eq!(parse("cost: $${").unwrap(), vec!(literal("cost: ${")));
+    }
+
+    #[test]
+    fn rejects_unterminated_placeholder() {
+        assert!(parse("${HOST_IP").is_err());
+        assert!(parse("${HOST_IP}:${env:PORT").is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_placeholder() {
+        assert!(parse("${HOME}").is_err());
+        assert!(parse("${env:}").is_err());
+    }
+}
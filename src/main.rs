@@ -13,10 +13,15 @@ extern crate core;
 extern crate log4rs;
 extern crate nix;
 #[macro_use] extern crate hyper;
+#[macro_use] extern crate lazy_static;
 extern crate argparse;
+extern crate inotify;
 extern crate serde;
 extern crate serde_json;
+extern crate time;
+extern crate toml;
 extern crate url;
+extern crate yaml_rust;
 
 // internal mods
 #[macro_use] mod utils;
@@ -26,22 +31,26 @@ mod human_uri;
 mod consul;
 mod docker;
 mod dispatcher;
+mod status;
+mod api;
+mod metrics;
+mod signals;
+mod config;
+mod formats;
+mod diff;
+mod interpolate;
+mod commands;
+mod runtime;
+mod source;
 
-// traits
-use std::str::FromStr;
 // std
+use std::env;
 use std::process::exit;
-// external
-use nix::sys::signal;
+use std::sync::mpsc;
+use std::thread;
 // interal
 
-extern fn handle_sigint(_:i32) {
-    println!("SIGINT");
-    exit(0);
-}
-
-
-fn initialize_logging(level: log::LogLevelFilter) {
+fn logging_config(level: log::LogLevelFilter) -> log4rs::config::Config {
     use log4rs::{config,appender};
     let root = config::Root::builder(log::LogLevelFilter::Error)
         .appender("stderr".to_string());
@@ -52,10 +61,40 @@ fn initialize_logging(level: log::LogLevelFilter) {
         .appender(config::Appender::builder("stderr".to_string(),
                                             console).build())
         .logger(logger.build());
-    log4rs::init_config(config.build().unwrap()).unwrap();
+    config.build().unwrap()
+}
+
+fn initialize_logging(level: log::LogLevelFilter) -> log4rs::Handle {
+    log4rs::init_config(logging_config(level)).unwrap()
+}
+
+fn load_config(cli_config: &config::Config, config_path: &Option<String>)
+               -> Result<config::Config, config::ConfigError>
+{
+    match config_path {
+        &Some(ref path) => cli_config.with_file(path),
+        &None => Ok(cli_config.clone())
+    }
+}
+
+/// Returns arguments for subcommand parser: program name is joined with
+/// subcommand, so it shows up in usage
+fn subcommand_args(args: &[String]) -> Vec<String> {
+    let mut sub_args = vec!(format!("{} {}", args[0], args[1]));
+    sub_args.extend(args[2..].iter().cloned());
+    sub_args
 }
 
 fn main() {
+    let args: Vec<String> = env::args().collect();
+    match args.get(1).map(|s| s.as_str()) {
+        Some("validate") => exit(commands::validate(subcommand_args(&args))),
+        Some("render") => exit(commands::render(subcommand_args(&args))),
+        _ => watch()
+    }
+}
+
+fn watch() {
     let mut consul_endpoint = "127.0.0.1:8500".to_string();
     let mut docker_endpoint = "127.0.0.1:2376".to_string();
     let consul_env = "CONSUL_AGENT";
@@ -64,12 +103,24 @@ fn main() {
 be set via {} env var; default: {}", consul_env, consul_endpoint);
     let docker_help = format!("Address of docker server to query; can \
 be set via {} env var; default: {}", docker_env, docker_endpoint);
+    let mut opt_consul_token:Option<String> = None;
+    let mut opt_config_path:Option<String> = None;
     let mut opt_consul_key:Option<String> = None;
+    let mut opt_spec_url:Option<String> = None;
+    let mut opt_spec_file:Option<String> = None;
+    let mut opt_spec_format:Option<spec::Format> = None;
+    let mut opt_lock_key:Option<String> = None;
+    let mut lock_ttl:u64 = 15;
+    let mut opt_status_key:Option<String> = None;
+    let mut opt_api_addr:Option<String> = None;
+    let mut dry_run = false;
+    let mut opt_shutdown_policy:Option<dispatcher::ShutdownPolicy> = None;
     let mut log_level = log::LogLevelFilter::Debug;
     {
         let mut ap = argparse::ArgumentParser::new();
         ap.set_description("Condo: watch for consul key and \
-run docker container.");
+run docker container. Use `condo validate --help` and `condo render \
+--help` to see how to check specs without running them.");
         ap.add_option(&["-V", "--version"],
                       argparse::Print(env!("CARGO_PKG_VERSION").to_string()),
                       "Show version");
@@ -81,40 +132,239 @@ run docker container.");
             .envvar(docker_env)
             .add_option(&["--docker"], argparse::Store,
                         &docker_help);
+        ap.refer(&mut opt_consul_token)
+            .envvar("CONSUL_TOKEN")
+            .add_option(&["--consul-token"], argparse::StoreOption,
+                        "ACL token for consul requests; can be set via \
+CONSUL_TOKEN env var");
+        ap.refer(&mut opt_config_path)
+            .add_option(&["--config"], argparse::StoreOption,
+                        "Json file with consul, consul_token, docker and \
+loglevel settings; they override command line and are re-read on SIGHUP");
         ap.refer(&mut opt_consul_key)
             .add_argument("consul_key", argparse::StoreOption,
-                          "Consul key to watch")
-            .required();
+                          "Consul key to watch");
+        ap.refer(&mut opt_spec_url)
+            .add_option(&["--spec-url"], argparse::StoreOption,
+                        "Poll spec from http url instead of consul key");
+        ap.refer(&mut opt_spec_file)
+            .add_option(&["--spec-file"], argparse::StoreOption,
+                        "Watch spec in local file instead of consul key; \
+spec is redeployed whenever file changes");
+        ap.refer(&mut opt_spec_format)
+            .add_option(&["--spec-format"], argparse::StoreOption,
+                        "Spec format: json, yaml or toml; guessed from \
+spec file extension or content by default");
+        ap.refer(&mut opt_lock_key)
+            .add_option(&["--lock"], argparse::StoreOption,
+                        "Consul key to lock before deploying; only \
+one condo holding the lock will run the spec");
+        ap.refer(&mut lock_ttl)
+            .add_option(&["--lock-ttl"], argparse::Store,
+                        "TTL of consul session holding the lock, \
+in seconds, at least 10; default: 15");
+        ap.refer(&mut opt_status_key)
+            .add_option(&["--status-key"], argparse::StoreOption,
+                        "Consul key to publish deploy status to; \
+should be unique per host");
+        ap.refer(&mut opt_api_addr)
+            .add_option(&["--api"], argparse::StoreOption,
+                        "Address to serve status and control api on, \
+e.g. 127.0.0.1:8080");
+        ap.refer(&mut dry_run)
+            .add_option(&["--dry-run"], argparse::StoreTrue,
+                        "Watch specs and run dispatcher, but only log \
+docker operations instead of performing them");
+        ap.refer(&mut opt_shutdown_policy)
+            .add_option(&["--on-shutdown"], argparse::StoreOption,
+                        "What to do with containers on SIGINT/SIGTERM: \
+leave or stop; default: leave, or stop with --lock");
         ap.refer(&mut log_level)
             .envvar("CONDO_LOG_LEVEL")
             .add_option(&["--loglevel"], argparse::Store,
                         "Set log level");
         ap.parse_args_or_exit();
     }
-    initialize_logging(log_level);
-    unsafe {
-        let sig_action = signal::SigAction::new(handle_sigint,
-                                                signal::SockFlag::empty(),
-                                                signal::SigSet::empty());
-        ignore_result!(signal::sigaction(signal::SIGINT, &sig_action));
+    // consul rejects session TTLs below 10s
+    if opt_lock_key.is_some() && lock_ttl < 10 {
+        println_err!("--lock-ttl should be at least 10 seconds");
+        exit(2);
     }
-    // opt_consul_key should not be None here, so unwrap safely
-    let consul_key = opt_consul_key.unwrap();
-    info!("Will watch for consul key: {}", consul_key);
-    let consul = consul::Consul::new(&consul_endpoint);
-    let docker = docker::Docker::new(&docker_endpoint);
-    let rx_json_specs = consul.watch_key(&consul_key);
-    let dispatcher = dispatcher::Dispatcher::new(docker);
-    let (_, tx_events) = dispatcher.start();
-    for json_spec in rx_json_specs.iter() {
-        debug!("Received json spec: {}", json_spec);
-        match spec::Spec::from_str(&json_spec) {
-            Ok(spec) => {
-                ignore_result!(tx_events.send(event::Event::NewSpec(spec)));
-            },
+    // standby condo takes the lock after leader exits, so containers left
+    // running would be run twice
+    let shutdown_policy = match (opt_shutdown_policy, &opt_lock_key) {
+        (Some(dispatcher::ShutdownPolicy::Leave), &Some(_)) => {
+            println_err!("--on-shutdown leave can't be used with --lock");
+            exit(2);
+        },
+        (Some(policy), _) => policy,
+        (None, &Some(_)) => dispatcher::ShutdownPolicy::Stop,
+        (None, &None) => dispatcher::ShutdownPolicy::Leave
+    };
+    let cli_config = config::Config{
+        consul: consul_endpoint,
+        consul_token: opt_consul_token,
+        docker: docker_endpoint,
+        log_level: log_level
+    };
+    let config = match load_config(&cli_config, &opt_config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            println_err!("{}", e);
+            exit(1);
+        }
+    };
+    let logging = initialize_logging(config.log_level);
+    let rx_signals = signals::install();
+    let spec_format = opt_spec_format.or(
+        opt_spec_file.as_ref().and_then(|path| spec::Format::from_path(path)));
+    let spec_source: Box<source::SpecSource> = match (opt_consul_key,
+                                                      opt_spec_url,
+                                                      opt_spec_file) {
+        (Some(consul_key), None, None) => {
+            info!("Will watch for consul key: {}", consul_key);
+            let consul = consul::Consul::from_config(&config);
+            Box::new(source::ConsulSource::new(consul, &consul_key))
+        },
+        (None, Some(spec_url), None) => {
+            info!("Will poll spec url: {}", spec_url);
+            Box::new(source::HttpSource::new(&spec_url))
+        },
+        (None, None, Some(spec_file)) => {
+            info!("Will watch spec file: {}", spec_file);
+            Box::new(source::FileSource::new(spec_file))
+        },
+        _ => {
+            println_err!("Exactly one of consul_key, --spec-url or --spec-file \
+should be given");
+            exit(2);
+        }
+    };
+    let runtime: Box<runtime::ContainerRuntime + Send> = if dry_run {
+        info!("Dry run: docker operations will only be logged");
+        Box::new(runtime::DryRun::new())
+    } else {
+        Box::new(docker::Docker::new(&config.docker))
+    };
+    let (tx_reload_watch, rx_reload_watch) = mpsc::channel();
+    let rx_raw_specs = spec_source.watch(rx_reload_watch);
+    let resolver = interpolate::HostResolver::new(
+        consul::Consul::from_config(&config));
+    let mut dispatcher = dispatcher::Dispatcher::new(runtime, Box::new(resolver))
+        .on_shutdown(shutdown_policy);
+    if !dry_run {
+        dispatcher = dispatcher.register_services(consul::Consul::from_config(&config));
+    }
+    if opt_lock_key.is_some() {
+        dispatcher = dispatcher.require_leadership();
+    }
+    // consul clients of status and lock threads get reloaded config too
+    let mut reload_senders = vec!();
+    if let Some(status_key) = opt_status_key {
+        info!("Will publish status to consul key: {}", status_key);
+        let status_consul = consul::Consul::from_config(&config);
+        let (tx_reload_status, rx_reload_status) = mpsc::channel();
+        reload_senders.push(tx_reload_status);
+        status::publish(status_consul, status_key, dispatcher.subscribe(),
+                        rx_reload_status);
+    }
+    let rx_api_statuses = dispatcher.subscribe();
+    let (dispatcher_handle, tx_events) = dispatcher.start();
+    // keep server listening while main is running
+    let _api = opt_api_addr.map(|addr| {
+        info!("Will serve api on: {}", addr);
+        match api::serve(&addr, rx_api_statuses, tx_events.clone()) {
+            Ok(listening) => listening,
             Err(e) => {
-                warn!("Error while parsing spec: {}, ignore...", e);
+                error!("Can't start api server on {}: {}", addr, e);
+                exit(1);
             }
         }
+    });
+    let (tx_release_lock, rx_release_lock) = mpsc::channel();
+    let lock_handle = opt_lock_key.map(|lock_key| {
+        info!("Will hold lock on consul key: {}", lock_key);
+        let lock_consul = consul::Consul::from_config(&config);
+        let (tx_reload_lock, rx_reload_lock) = mpsc::channel();
+        reload_senders.push(tx_reload_lock);
+        let rx_leadership = lock_consul.hold_lock(&lock_key, lock_ttl, rx_reload_lock,
+                                                  rx_release_lock);
+        let tx_leadership_events = tx_events.clone();
+        // finishes, when lock is released
+        thread::spawn(move || {
+            for leader in rx_leadership.iter() {
+                let event = if leader {
+                    event::Event::LeadershipAcquired
+                } else {
+                    event::Event::LeadershipLost
+                };
+                ignore_result!(tx_leadership_events.send(event));
+            }
+        })
+    });
+    let (tx_reload_docker_events, rx_reload_docker_events) = mpsc::channel();
+    if !dry_run {
+        let events_docker = docker::Docker::new(&config.docker);
+        let rx_deaths = events_docker.watch_events(rx_reload_docker_events);
+        let tx_death_events = tx_events.clone();
+        thread::spawn(move || {
+            for death in rx_deaths.iter() {
+                ignore_result!(tx_death_events.send(
+                    event::Event::ContainerDied(death.id, death.oom_killed)));
+            }
+        });
     }
+    let tx_spec_events = tx_events.clone();
+    thread::spawn(move || {
+        for raw_spec in rx_raw_specs.iter() {
+            debug!("Received spec: {}", raw_spec);
+            match spec::Spec::parse(&raw_spec, spec_format) {
+                Ok(spec) => match spec.validate() {
+                    Ok(_) => {
+                        ignore_result!(tx_spec_events.send(event::Event::NewSpec(spec)));
+                    },
+                    Err(errors) => {
+                        warn!("{}\nignore...", errors);
+                    }
+                },
+                Err(e) => {
+                    warn!("Error while parsing spec: {}, ignore...", e);
+                }
+            }
+        }
+    });
+    for signal in rx_signals.iter() {
+        match signal {
+            signals::Signal::Terminate => {
+                info!("Received termination signal, shutting down...");
+                ignore_result!(tx_events.send(event::Event::Shutdown));
+                break;
+            },
+            signals::Signal::Reload => {
+                info!("Received SIGHUP, reloading configuration...");
+                match load_config(&cli_config, &opt_config_path) {
+                    Ok(config) => {
+                        logging.set_config(logging_config(config.log_level));
+                        ignore_result!(tx_reload_watch.send(config.clone()));
+                        ignore_result!(tx_reload_docker_events.send(config.clone()));
+                        for tx_reload in reload_senders.iter() {
+                            ignore_result!(tx_reload.send(config.clone()));
+                        }
+                        ignore_result!(tx_events.send(event::Event::Reload(config)));
+                    },
+                    Err(e) => error!("{}, keeping old configuration", e)
+                }
+            }
+        }
+    }
+    // Dispatcher finishes, when it has processed all events before
+    // shutdown, so no docker calls will be interrupted
+    ignore_result!(dispatcher_handle.join());
+    // containers are stopped by now, so standby can take the lock
+    // without waiting for session to expire
+    if let Some(lock_handle) = lock_handle {
+        ignore_result!(tx_release_lock.send(()));
+        ignore_result!(lock_handle.join());
+    }
+    exit(0);
 }
@@ -0,0 +1,141 @@
+// std
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Kind {
+    Counter,
+    Gauge,
+    Summary
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            &Kind::Counter => "counter",
+            &Kind::Gauge => "gauge",
+            &Kind::Summary => "summary"
+        }
+    }
+}
+
+pub struct Metric {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub kind: Kind
+}
+
+pub const DEPLOYS: Metric = Metric{
+    name: "condo_deploys_total",
+    help: "Deploys by result: started, succeeded, failed",
+    kind: Kind::Counter
+};
+
+pub const ROLLBACKS: Metric = Metric{
+    name: "condo_rollbacks_total",
+    help: "Rollbacks to last stable deploy",
+    kind: Kind::Counter
+};
+
+pub const STATE: Metric = Metric{
+    name: "condo_state",
+    help: "1 for current dispatcher state, 0 for others",
+    kind: Kind::Gauge
+};
+
+pub const STATE_SECONDS: Metric = Metric{
+    name: "condo_state_seconds_total",
+    help: "Time spent by dispatcher in each state",
+    kind: Kind::Counter
+};
+
+pub const CONSUL_ERRORS: Metric = Metric{
+    name: "condo_consul_watch_errors_total",
+    help: "Errors while watching consul key by kind",
+    kind: Kind::Counter
+};
+
+pub const DOCKER_REQUESTS: Metric = Metric{
+    name: "condo_docker_request_duration_seconds",
+    help: "Duration of docker api operations",
+    kind: Kind::Summary
+};
+
+pub const DOCKER_ERRORS: Metric = Metric{
+    name: "condo_docker_errors_total",
+    help: "Docker api errors by operation and kind",
+    kind: Kind::Counter
+};
+
+struct Family {
+    help: &'static str,
+    kind: Kind,
+    // (suffix, rendered labels) -> value
+    values: BTreeMap<(&'static str, String), f64>
+}
+
+lazy_static! {
+    static ref REGISTRY: Mutex<BTreeMap<&'static str, Family>> =
+        Mutex::new(BTreeMap::new());
+}
+
+fn escape(value: &str) -> String {
+    value.replace("\\", "\\\\").replace("\"", "\\\"").replace("\n", "\\n")
+}
+
+fn render_labels(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+    let pairs = labels.iter()
+        .map(|&(k, v)| format!("{}=\"{}\"", k, escape(v)))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", pairs.join(","))
+}
+
+fn update<F>(metric: &Metric, suffix: &'static str, labels: &[(&str, &str)], f: F)
+    where F: FnOnce(&mut f64)
+{
+    let mut registry = REGISTRY.lock().unwrap();
+    let family = registry.entry(metric.name).or_insert_with(|| Family{
+        help: metric.help,
+        kind: metric.kind,
+        values: BTreeMap::new()
+    });
+    f(family.values.entry((suffix, render_labels(labels))).or_insert(0.0));
+}
+
+pub fn inc(metric: &Metric, labels: &[(&str, &str)]) {
+    add(metric, labels, 1.0)
+}
+
+pub fn add(metric: &Metric, labels: &[(&str, &str)], value: f64) {
+    debug_assert!(metric.kind == Kind::Counter);
+    update(metric, "", labels, |v| *v += value)
+}
+
+pub fn set(metric: &Metric, labels: &[(&str, &str)], value: f64) {
+    debug_assert!(metric.kind == Kind::Gauge);
+    update(metric, "", labels, |v| *v = value)
+}
+
+pub fn observe(metric: &Metric, labels: &[(&str, &str)], value: f64) {
+    debug_assert!(metric.kind == Kind::Summary);
+    update(metric, "_sum", labels, |v| *v += value);
+    update(metric, "_count", labels, |v| *v += 1.0);
+}
+
+/// Renders all metrics in prometheus text format
+pub fn render() -> String {
+    let registry = REGISTRY.lock().unwrap();
+    let mut out = String::new();
+    for (name, family) in registry.iter() {
+        ignore_result!(writeln!(out, "# HELP {} {}", name, family.help));
+        ignore_result!(writeln!(out, "# TYPE {} {}", name, family.kind.name()));
+        for (&(suffix, ref labels), value) in family.values.iter() {
+            ignore_result!(writeln!(out, "{}{}{} {}", name, suffix, labels, value));
+        }
+    }
+    out
+}
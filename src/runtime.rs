@@ -0,0 +1,354 @@
+// std
+use std::cell::Cell;
+use std::collections::BTreeMap;
+#[cfg(test)] use std::collections::HashSet;
+#[cfg(test)] use std::sync::{Arc, Mutex};
+// internal
+use docker::{Docker, DockerError, ContainerConfig, ContainerInfo, LogLine, image_name};
+use spec::Image;
+
+/// Operations dispatcher needs to run deploys
+pub trait ContainerRuntime {
+    fn pull_image(&self, image: &Image) -> Result<(), DockerError>;
+    /// Creates container and returns its id
+    fn create_container(&self, name: Option<&str>, config: &ContainerConfig)
+                        -> Result<String, DockerError>;
+    fn start_container(&self, id: &str) -> Result<(), DockerError>;
+    /// Stops container, killing it after `timeout` seconds
+    fn stop_container(&self, id: &str, timeout: u16) -> Result<(), DockerError>;
+    fn remove_container(&self, id: &str) -> Result<(), DockerError>;
+    fn inspect_container(&self, id: &str) -> Result<ContainerInfo, DockerError>;
+    /// Blocks until container stops and returns its exit code
+    fn wait_container(&self, id: &str) -> Result<i64, DockerError>;
+    /// Returns container output lines, see `Docker::logs`; iterator can be
+    /// consumed in other thread
+    fn logs(&self, id: &str, follow: bool, tail: Option<usize>)
+            -> Result<Box<Iterator<Item=LogLine> + Send>, DockerError>;
+    fn set_endpoint(&mut self, raw_uri: &str);
+}
+
+impl ContainerRuntime for Docker {
+    fn pull_image(&self, image: &Image) -> Result<(), DockerError> {
+        Docker::pull_image(self, image)
+    }
+
+    fn create_container(&self, name: Option<&str>, config: &ContainerConfig)
+                        -> Result<String, DockerError>
+    {
+        Docker::create_container(self, name, config)
+    }
+
+    fn start_container(&self, id: &str) -> Result<(), DockerError> {
+        Docker::start_container(self, id)
+    }
+
+    fn stop_container(&self, id: &str, timeout: u16) -> Result<(), DockerError> {
+        Docker::stop_container(self, id, timeout)
+    }
+
+    fn remove_container(&self, id: &str) -> Result<(), DockerError> {
+        Docker::remove_container(self, id)
+    }
+
+    fn inspect_container(&self, id: &str) -> Result<ContainerInfo, DockerError> {
+        Docker::inspect_container(self, id)
+    }
+
+    fn wait_container(&self, id: &str) -> Result<i64, DockerError> {
+        Docker::wait_container(self, id)
+    }
+
+    fn logs(&self, id: &str, follow: bool, tail: Option<usize>)
+            -> Result<Box<Iterator<Item=LogLine> + Send>, DockerError>
+    {
+        let lines = try!(Docker::logs(self, id, follow, tail));
+        Ok(Box::new(lines))
+    }
+
+    fn set_endpoint(&mut self, raw_uri: &str) {
+        Docker::set_endpoint(self, raw_uri)
+    }
+}
+
+/// Runtime, which only logs operations, so rollout of spec could be
+/// checked without touching containers
+pub struct DryRun {
+    // containers get numbered ids, so old and new ones of the same image
+    // can be told apart in log
+    next_id: Cell<u64>
+}
+
+impl DryRun {
+    pub fn new() -> Self {
+        DryRun{
+            next_id: Cell::new(1)
+        }
+    }
+}
+
+impl ContainerRuntime for DryRun {
+    fn pull_image(&self, image: &Image) -> Result<(), DockerError> {
+        info!("[dry-run] pull image {}", image_name(image));
+        Ok(())
+    }
+
+    fn create_container(&self, name: Option<&str>, config: &ContainerConfig)
+                        -> Result<String, DockerError>
+    {
+        let id = format!("dry-run-{}", self.next_id.get());
+        self.next_id.set(self.next_id.get() + 1);
+        info!("[dry-run] create container {} {:?}: {:?}", id, name, config.redacted());
+        Ok(id)
+    }
+
+    fn start_container(&self, id: &str) -> Result<(), DockerError> {
+        info!("[dry-run] start container {}", id);
+        Ok(())
+    }
+
+    fn stop_container(&self, id: &str, timeout: u16) -> Result<(), DockerError> {
+        info!("[dry-run] stop container {} with timeout {}", id, timeout);
+        Ok(())
+    }
+
+    fn remove_container(&self, id: &str) -> Result<(), DockerError> {
+        info!("[dry-run] remove container {}", id);
+        Ok(())
+    }
+
+    fn inspect_container(&self, id: &str) -> Result<ContainerInfo, DockerError> {
+        Ok(ContainerInfo{
+            id: id.to_string(),
+            running: true,
+            exit_code: 0,
+            oom_killed: false,
+            ports: BTreeMap::new()
+        })
+    }
+
+    fn wait_container(&self, id: &str) -> Result<i64, DockerError> {
+        info!("[dry-run] wait for container {}", id);
+        Ok(0)
+    }
+
+    fn logs(&self, _: &str, _: bool, _: Option<usize>)
+            -> Result<Box<Iterator<Item=LogLine> + Send>, DockerError>
+    {
+        Ok(Box::new(Vec::new().into_iter()))
+    }
+
+    fn set_endpoint(&mut self, raw_uri: &str) {
+        info!("[dry-run] switch endpoint to {}", raw_uri);
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct FakeContainer {
+    pub name: Option<String>,
+    pub config: ContainerConfig,
+    pub info: ContainerInfo,
+    pub output: Vec<LogLine>
+}
+
+#[cfg(test)]
+#[derive(Debug, Default)]
+struct FakeState {
+    images: HashSet<String>,
+    containers: BTreeMap<String, FakeContainer>,
+    failing: HashSet<String>,
+    operations: Vec<String>,
+    next_id: usize,
+    next_port: u16
+}
+
+/// In-memory runtime for exercising dispatcher deterministically: it
+/// records all operations and can be told to fail any of them. Clones
+/// share state, so one can be given to dispatcher and other kept to
+/// inspect and manipulate it.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct Fake {
+    state: Arc<Mutex<FakeState>>
+}
+
+#[cfg(test)]
+impl Fake {
+    pub fn new() -> Self {
+        Fake::default()
+    }
+
+    /// Makes every following call of operation (e.g. "start_container")
+    /// fail, until `recover` is called
+    pub fn fail(&self, operation: &str) {
+        self.state.lock().unwrap().failing.insert(operation.to_string());
+    }
+
+    pub fn recover(&self, operation: &str) {
+        self.state.lock().unwrap().failing.remove(operation);
+    }
+
+    /// Returns performed operations, e.g. "start_container fake-1"
+    pub fn operations(&self) -> Vec<String> {
+        self.state.lock().unwrap().operations.clone()
+    }
+
+    pub fn containers(&self) -> BTreeMap<String, FakeContainer> {
+        self.state.lock().unwrap().containers.clone()
+    }
+
+    /// Simulates exit of running container
+    pub fn exit(&self, id: &str, exit_code: i64, oom_killed: bool) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(container) = state.containers.get_mut(id) {
+            container.info.running = false;
+            container.info.exit_code = exit_code;
+            container.info.oom_killed = oom_killed;
+        }
+    }
+
+    /// Simulates output line of container
+    pub fn print(&self, id: &str, stderr: bool, text: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(container) = state.containers.get_mut(id) {
+            container.output.push(LogLine{stderr: stderr, text: text.to_string()});
+        }
+    }
+
+    /// Records operation and returns error, if it should fail
+    fn perform(&self, operation: &str, target: &str) -> Result<(), DockerError> {
+        let mut state = self.state.lock().unwrap();
+        state.operations.push(format!("{} {}", operation, target));
+        if state.failing.contains(operation) {
+            Err(DockerError::RequestError(format!("{} failed", operation)))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn update<F>(&self, id: &str, f: F) -> Result<(), DockerError>
+        where F: FnOnce(&mut BTreeMap<String, FakeContainer>)
+    {
+        let mut state = self.state.lock().unwrap();
+        if !state.containers.contains_key(id) {
+            return Err(DockerError::HTTPError(
+                "404 Not Found".to_string(), format!("No such container: {}", id)));
+        }
+        f(&mut state.containers);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl ContainerRuntime for Fake {
+    fn pull_image(&self, image: &Image) -> Result<(), DockerError> {
+        let name = image_name(image);
+        try!(self.perform("pull_image", &name));
+        self.state.lock().unwrap().images.insert(name);
+        Ok(())
+    }
+
+    fn create_container(&self, name: Option<&str>, config: &ContainerConfig)
+                        -> Result<String, DockerError>
+    {
+        try!(self.perform("create_container", &config.image));
+        let mut state = self.state.lock().unwrap();
+        if !state.images.contains(&config.image) {
+            return Err(DockerError::HTTPError(
+                "404 Not Found".to_string(), format!("No such image: {}", config.image)));
+        }
+        state.next_id += 1;
+        let id = format!("fake-{}", state.next_id);
+        state.containers.insert(id.clone(), FakeContainer{
+            name: name.map(|n| n.to_string()),
+            config: config.clone(),
+            info: ContainerInfo{
+                id: id.clone(),
+                running: false,
+                exit_code: 0,
+                oom_killed: false,
+                ports: BTreeMap::new()
+            },
+            output: vec!()
+        });
+        Ok(id)
+    }
+
+    fn start_container(&self, id: &str) -> Result<(), DockerError> {
+        try!(self.perform("start_container", id));
+        let mut state = self.state.lock().unwrap();
+        let FakeState{ref mut containers, ref mut next_port, ..} = *state;
+        match containers.get_mut(id) {
+            Some(container) => {
+                // bind ports like docker does: dynamic ones from 32768
+                for (key, bindings) in container.config.host_config.port_bindings.iter() {
+                    let port = match bindings[0].host_port.parse() {
+                        Ok(port) => port,
+                        Err(_) => {
+                            *next_port += 1;
+                            32767 + *next_port
+                        }
+                    };
+                    container.info.ports.insert(key.clone(), port);
+                }
+                container.info.running = true;
+                Ok(())
+            },
+            None => Err(DockerError::HTTPError(
+                "404 Not Found".to_string(), format!("No such container: {}", id)))
+        }
+    }
+
+    fn stop_container(&self, id: &str, _: u16) -> Result<(), DockerError> {
+        try!(self.perform("stop_container", id));
+        self.update(id, |containers| {
+            containers.get_mut(id).unwrap().info.running = false;
+        })
+    }
+
+    fn remove_container(&self, id: &str) -> Result<(), DockerError> {
+        try!(self.perform("remove_container", id));
+        self.update(id, |containers| {
+            containers.remove(id);
+        })
+    }
+
+    fn inspect_container(&self, id: &str) -> Result<ContainerInfo, DockerError> {
+        try!(self.perform("inspect_container", id));
+        match self.state.lock().unwrap().containers.get(id) {
+            Some(container) => Ok(container.info.clone()),
+            None => Err(DockerError::HTTPError(
+                "404 Not Found".to_string(), format!("No such container: {}", id)))
+        }
+    }
+
+    // doesn't block: exit code of running container is returned too
+    fn wait_container(&self, id: &str) -> Result<i64, DockerError> {
+        try!(self.perform("wait_container", id));
+        match self.state.lock().unwrap().containers.get(id) {
+            Some(container) => Ok(container.info.exit_code),
+            None => Err(DockerError::HTTPError(
+                "404 Not Found".to_string(), format!("No such container: {}", id)))
+        }
+    }
+
+    // returns output printed so far, even when following
+    fn logs(&self, id: &str, _: bool, tail: Option<usize>)
+            -> Result<Box<Iterator<Item=LogLine> + Send>, DockerError>
+    {
+        try!(self.perform("logs", id));
+        match self.state.lock().unwrap().containers.get(id) {
+            Some(container) => {
+                let output = &container.output;
+                let skip = tail.map_or(0, |n| output.len().saturating_sub(n));
+                Ok(Box::new(output[skip..].to_vec().into_iter()))
+            },
+            None => Err(DockerError::HTTPError(
+                "404 Not Found".to_string(), format!("No such container: {}", id)))
+        }
+    }
+
+    fn set_endpoint(&mut self, raw_uri: &str) {
+        ignore_result!(self.perform("set_endpoint", raw_uri));
+    }
+}
@@ -0,0 +1,58 @@
+// ext libs
+use nix::sys::signal;
+// std
+use std::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+// internal
+use utils::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    // SIGINT or SIGTERM
+    Terminate,
+    // SIGHUP
+    Reload
+}
+
+// Signal handlers can only do async-signal-safe things, so they just set
+// flags, which are polled from normal thread
+static TERMINATE: AtomicBool = ATOMIC_BOOL_INIT;
+static RELOAD: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern fn handle_terminate(_:i32) {
+    TERMINATE.store(true, Ordering::SeqCst);
+}
+
+extern fn handle_reload(_:i32) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// Installs signal handlers and returns receiver of caught signals
+pub fn install() -> mpsc::Receiver<Signal> {
+    unsafe {
+        let sig_action = signal::SigAction::new(handle_terminate,
+                                                signal::SockFlag::empty(),
+                                                signal::SigSet::empty());
+        ignore_result!(signal::sigaction(signal::SIGINT, &sig_action));
+        ignore_result!(signal::sigaction(signal::SIGTERM, &sig_action));
+        let reload_action = signal::SigAction::new(handle_reload,
+                                                   signal::SockFlag::empty(),
+                                                   signal::SigSet::empty());
+        ignore_result!(signal::sigaction(signal::SIGHUP, &reload_action));
+    }
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let flags = [(&TERMINATE, Signal::Terminate), (&RELOAD, Signal::Reload)];
+        loop {
+            for &(flag, signal) in flags.iter() {
+                if flag.swap(false, Ordering::SeqCst) && tx.send(signal).is_err() {
+                    return;
+                }
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    });
+    rx
+}
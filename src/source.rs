@@ -0,0 +1,245 @@
+// ext libs
+use hyper;
+use hyper::header;
+use hyper::status::StatusCode;
+use inotify::INotify;
+use inotify::ffi::{IN_CLOSE_WRITE, IN_MOVED_TO, IN_CREATE, IN_DELETE};
+use time;
+// traits
+use std::io::Read;
+// std
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+// internal
+use config::Config;
+use consul::Consul;
+use utils::*;
+
+/// Something, that produces stream of raw specs
+pub trait SpecSource {
+    /// Starts watching for specs in background; when config is received
+    /// from `reload`, source should apply it and re-read spec from scratch
+    fn watch(self: Box<Self>, reload: mpsc::Receiver<Config>) -> mpsc::Receiver<String>;
+}
+
+/// Consul key, watched with blocking queries
+pub struct ConsulSource {
+    consul: Consul,
+    key: String
+}
+
+impl ConsulSource {
+    pub fn new(consul: Consul, key: &str) -> Self {
+        ConsulSource{
+            consul: consul,
+            key: key.to_owned()
+        }
+    }
+}
+
+impl SpecSource for ConsulSource {
+    fn watch(self: Box<Self>, reload: mpsc::Receiver<Config>) -> mpsc::Receiver<String> {
+        let source = *self;
+        source.consul.watch_key(source.key, reload)
+    }
+}
+
+/// Local file, re-read when it is changed; changes are debounced, so file
+/// is read once, when it is written in several steps
+pub struct FileSource {
+    path: PathBuf
+}
+
+impl FileSource {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        FileSource{
+            path: path.into()
+        }
+    }
+}
+
+fn read_file(path: &PathBuf) -> Result<String, String> {
+    let mut content = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .map(|_| content)
+        .map_err(|e| format!("Can't read {}: {}", path.display(), error_details(&e)))
+}
+
+// how long file should stay untouched after change before it is re-read
+const FILE_DEBOUNCE_MS: u64 = 300;
+
+/// Sends content of file, if it differs from last sent; returns false,
+/// when receiver is gone
+fn send_if_changed(path: &PathBuf, last: &mut Option<String>,
+                   tx: &mpsc::Sender<String>) -> bool
+{
+    match read_file(path) {
+        Ok(ref content) if Some(content) == last.as_ref() => {
+            debug!("No new content in {}...", path.display());
+            true
+        },
+        Ok(content) => {
+            *last = Some(content.clone());
+            tx.send(content).is_ok()
+        },
+        Err(e) => {
+            error!("{}", e);
+            true
+        }
+    }
+}
+
+/// Fallback for systems, where inotify is not available
+fn poll_file(path: PathBuf, reload: mpsc::Receiver<Config>, tx: mpsc::Sender<String>) {
+    let mut last = None;
+    loop {
+        if let Ok(_) = reload.try_recv() {
+            info!("Reloading watch of {}", path.display());
+            last = None;
+        }
+        if !send_if_changed(&path, &mut last, &tx) {
+            break;
+        }
+        sleep(5);
+    }
+}
+
+fn watch_file(path: PathBuf, reload: mpsc::Receiver<Config>, tx: mpsc::Sender<String>) {
+    // Watch directory rather than file itself: editors and config
+    // management replace files by renaming temporary ones over them, which
+    // would silently break watch on the old inode.
+    let dir = match path.parent() {
+        Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+        _ => PathBuf::from(".")
+    };
+    let file_name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => {
+            error!("Invalid spec file path: {}", path.display());
+            return;
+        }
+    };
+    let mut ino = match INotify::init().and_then(|mut ino| {
+        try!(ino.add_watch(&dir, IN_CLOSE_WRITE | IN_MOVED_TO | IN_CREATE | IN_DELETE));
+        Ok(ino)
+    }) {
+        Ok(ino) => ino,
+        Err(e) => {
+            warn!("Can't watch {} with inotify: {}, will poll it", dir.display(), e);
+            return poll_file(path, reload, tx);
+        }
+    };
+    let mut last = None;
+    if !send_if_changed(&path, &mut last, &tx) {
+        return;
+    }
+    // time of last change, which wasn't read yet
+    let mut changed_at: Option<u64> = None;
+    loop {
+        if let Ok(_) = reload.try_recv() {
+            info!("Reloading watch of {}", path.display());
+            last = None;
+            changed_at = Some(0);
+        }
+        match ino.available_events() {
+            Ok(events) => {
+                if events.iter().any(|e| e.name == file_name) {
+                    debug!("Spec file {} changed", path.display());
+                    changed_at = Some(time::precise_time_ns() / 1000000);
+                }
+            },
+            Err(e) => error!("Can't read inotify events: {}", e)
+        };
+        let now = time::precise_time_ns() / 1000000;
+        match changed_at {
+            Some(at) if now - at >= FILE_DEBOUNCE_MS => {
+                changed_at = None;
+                if !send_if_changed(&path, &mut last, &tx) {
+                    break;
+                }
+            },
+            _ => ()
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+impl SpecSource for FileSource {
+    fn watch(self: Box<Self>, reload: mpsc::Receiver<Config>) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        let path = self.path;
+        thread::spawn(move || watch_file(path, reload, tx));
+        rx
+    }
+}
+
+/// Http url, polled with conditional requests; when server doesn't send
+/// ETag, body is sent only if it differs from last sent one
+pub struct HttpSource {
+    url: String
+}
+
+impl HttpSource {
+    pub fn new(url: &str) -> Self {
+        HttpSource{
+            url: url.to_owned()
+        }
+    }
+}
+
+impl SpecSource for HttpSource {
+    fn watch(self: Box<Self>, reload: mpsc::Receiver<Config>) -> mpsc::Receiver<String> {
+        let (tx, rx) = mpsc::channel();
+        let url = self.url;
+        thread::spawn(move || {
+            let client = hyper::Client::new();
+            let mut etag: Option<header::EntityTag> = None;
+            let mut last: Option<String> = None;
+            loop {
+                if let Ok(_) = reload.try_recv() {
+                    info!("Reloading watch of {}", url);
+                    etag = None;
+                    last = None;
+                }
+                debug!("Get {}...", url);
+                let mut request = client.get(&url[..])
+                    .header(header::Connection::close());
+                if let Some(ref tag) = etag {
+                    request = request.header(
+                        header::IfNoneMatch::Items(vec!(tag.clone())));
+                }
+                match request.send() {
+                    Ok(ref response) if response.status == StatusCode::NotModified =>
+                        debug!("No new content received..."),
+                    Ok(mut response) => {
+                        let mut body = String::new();
+                        match response.read_to_string(&mut body) {
+                            Ok(_) if response.status == hyper::Ok => {
+                                etag = response.headers.get::<header::ETag>()
+                                    .map(|tag| tag.0.clone());
+                                if Some(&body) == last.as_ref() {
+                                    debug!("No new content in {}...", url);
+                                } else {
+                                    last = Some(body.clone());
+                                    if tx.send(body).is_err() {
+                                        break;
+                                    }
+                                }
+                            },
+                            Ok(_) => error!("Can't get {}: {} {}", url,
+                                            response.status, body),
+                            Err(e) => error!("Can't read {}: {}", url, e)
+                        }
+                    },
+                    Err(e) => error!("Can't get {}: {}", url, error_details(&e))
+                };
+                sleep(5);
+            }
+        });
+        rx
+    }
+}
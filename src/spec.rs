@@ -1,31 +1,66 @@
 
 // ext libs
 use serde_json;
-use serde::{Deserialize, Deserializer, de};
+use serde::{Serialize, Serializer, Deserialize, Deserializer, de};
 // traits
 use serde::de::Error;
 use std::str::FromStr;
+use std::hash::{Hash, Hasher};
+use std::fmt;
+use std::error;
+// std
+use std::collections::HashSet;
+use std::hash::SipHasher;
 // interntal
+use formats;
+use interpolate;
 
-#[derive(Deserialize, Debug, Clone)]
+/// Reference to env var value, stored outside of spec: either consul key
+/// (with stricter ACLs) or file on host. Exactly one should be set
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Secret {
+    #[serde(skip_serializing_if_none, default)]
+    pub consul: Option<String>,
+    #[serde(skip_serializing_if_none, default)]
+    pub file: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct EnvVar {
     pub name: String,
-    pub value: String
+    #[serde(default)]
+    pub value: String,
+    // resolved only when container is created, never put into logs or status
+    #[serde(skip_serializing_if_none, default)]
+    pub secret: Option<Secret>
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Image {
     pub name: String,
     pub tag: String
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum CheckMethod {
     Script(String),
     Http(String),
     HttpPath(String)
 }
 
+// yojson-compatible variant encoding: ["Http", "..."]
+impl Serialize for CheckMethod {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer
+    {
+        match self {
+            &CheckMethod::Script(ref s) => ("Script", s).serialize(serializer),
+            &CheckMethod::Http(ref s) => ("Http", s).serialize(serializer),
+            &CheckMethod::HttpPath(ref s) => ("HttpPath", s).serialize(serializer)
+        }
+    }
+}
+
 impl Deserialize for CheckMethod {
     fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error>
         where D: Deserializer
@@ -63,20 +98,35 @@ impl de::Visitor for CheckMethodEnumVisitor {
         try!(v.end());
         Ok(ret)
     }
+
+    // friendlier form: {"http": "..."}
+    fn visit_map<V>(&mut self, mut v: V) -> Result<Self::Value, V::Error>
+        where V: de::MapVisitor
+    {
+        let ret = match try!(v.visit_key::<String>()) {
+            Some(ref m) if m == "script" => CheckMethod::Script(try!(v.visit_value())),
+            Some(ref m) if m == "http" => CheckMethod::Http(try!(v.visit_value())),
+            Some(ref m) if m == "http_path" => CheckMethod::HttpPath(try!(v.visit_value())),
+            Some(m) => return Err(V::Error::unknown_field(&m)),
+            None => return Err(V::Error::missing_field("script, http or http_path"))
+        };
+        try!(v.end());
+        Ok(ret)
+    }
 }
 
 impl Default for CheckMethod {
     fn default() -> CheckMethod { CheckMethod::Script("echo".to_string()) }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Check {
     pub method: CheckMethod,
     pub interval: u16,
     pub timeout: u16
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Service {
     pub name: String,
     pub port: u16,
@@ -87,7 +137,7 @@ pub struct Service {
     pub host_port: Option<u16>
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Discovery {
     pub service: String,
     pub env: String,
@@ -97,13 +147,34 @@ pub struct Discovery {
     pub tag: Option<String>
 }
 
-#[derive(Deserialize, Debug, Clone)]
+/// Host path or named volume (no '/' in `from`) mounted at `to`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Volume {
     pub from: String,
-    pub to: String
+    pub to: String,
+    #[serde(default)]
+    pub read_only: bool
 }
 
-#[derive(Deserialize, Debug, Clone)]
+impl Volume {
+    pub fn is_named(&self) -> bool {
+        !self.from.contains('/')
+    }
+
+    /// Checks that `from` is absolute path or volume name; placeholders
+    /// should be interpolated before
+    pub fn check_from(&self) -> Result<(), String> {
+        if self.from.is_empty() {
+            Err("is empty".to_string())
+        } else if !self.is_named() && !self.from.starts_with('/') {
+            Err(format!("{:?} is neither absolute path nor volume name", self.from))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Log {
     #[serde(rename="type")]
     pub log_type: String,
@@ -112,12 +183,24 @@ pub struct Log {
 }
 
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Stop {
     Before,
     AfterTimeout(u16)
 }
 
+// ocaml's yojson Enum serialization: ["Before"], ["AfterTimeout", 10]
+impl Serialize for Stop {
+    fn serialize<S>(&self, serializer: &mut S) -> Result<(), S::Error>
+        where S: Serializer
+    {
+        match self {
+            &Stop::Before => ("Before",).serialize(serializer),
+            &Stop::AfterTimeout(t) => ("AfterTimeout", t).serialize(serializer)
+        }
+    }
+}
+
 // ocaml's yojson Enum parsing
 impl Deserialize for Stop {
     fn deserialize<D>(deserializer: &mut D) -> Result<Self, D::Error>
@@ -149,13 +232,35 @@ impl de::Visitor for StopEnumVisitor {
         try!(v.end());
         Ok(ret)
     }
+
+    // friendlier forms: "before" and {"after_timeout": 10}
+    fn visit_str<E>(&mut self, v: &str) -> Result<Self::Value, E>
+        where E: de::Error
+    {
+        match v {
+            "before" | "Before" => Ok(Stop::Before),
+            other => Err(E::unknown_field(other))
+        }
+    }
+
+    fn visit_map<V>(&mut self, mut v: V) -> Result<Self::Value, V::Error>
+        where V: de::MapVisitor
+    {
+        let ret = match try!(v.visit_key::<String>()) {
+            Some(ref k) if k == "after_timeout" => Stop::AfterTimeout(try!(v.visit_value())),
+            Some(k) => return Err(V::Error::unknown_field(&k)),
+            None => return Err(V::Error::missing_field("after_timeout"))
+        };
+        try!(v.end());
+        Ok(ret)
+    }
 }
 
 impl Default for Stop {
     fn default() -> Stop { Stop::AfterTimeout(10) }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Spec {
     pub image: Image,
     pub cmd: Vec<String>,
@@ -176,12 +281,434 @@ pub struct Spec {
     pub stop: Stop,
     pub kill_timeout: Option<u16>,
     #[serde(skip_serializing_if_none, default)]
-    pub log: Option<Log>
+    pub log: Option<Log>,
+    // skipped when empty, so fingerprints of specs without volumes don't change
+    #[serde(skip_serializing_if_empty, default)]
+    pub volumes: Vec<Volume>
+}
+
+/// Semantic problem in spec, with path to field, e.g. `services[0].port`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub path: String,
+    pub message: String
+}
+
+/// All problems found in spec
+#[derive(Debug)]
+pub struct ValidationErrors(pub Vec<ValidationError>);
+
+impl ValidationErrors {
+    fn add<P: Into<String>, M: Into<String>>(&mut self, path: P, message: M) {
+        self.0.push(ValidationError{
+            path: path.into(),
+            message: message.into()
+        });
+    }
+}
+
+impl error::Error for ValidationErrors {
+    fn description(&self) -> &str {
+        "Invalid spec"
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        try!(write!(f, "Invalid spec, {} problem(s) found:", self.0.len()));
+        for e in self.0.iter() {
+            try!(write!(f, "\n  {}: {}", e.path, e.message));
+        }
+        Ok(())
+    }
+}
+
+fn validate_env_name(errors: &mut ValidationErrors, path: String, name: &str) {
+    if name.is_empty() {
+        errors.add(path, "env var name is empty");
+    } else if name.contains('=') {
+        errors.add(path, format!("env var name {:?} contains '='", name));
+    }
+}
+
+fn validate_secret(errors: &mut ValidationErrors, path: String, secret: &Secret) {
+    match (&secret.consul, &secret.file) {
+        (&Some(ref key), &None) if key.is_empty() =>
+            errors.add(format!("{}.consul", path), "is empty"),
+        (&None, &Some(ref file)) if !file.starts_with('/') =>
+            errors.add(format!("{}.file", path),
+                       format!("{:?} is not an absolute path", file)),
+        (&Some(_), &None) | (&None, &Some(_)) => (),
+        _ => errors.add(path, "exactly one of consul and file should be set")
+    }
+}
+
+impl Spec {
+    /// Checks things, that json shape can't express; returns all found
+    /// problems at once
+    pub fn validate(&self) -> Result<(), ValidationErrors> {
+        let mut errors = ValidationErrors(vec!());
+        if self.image.name.is_empty() {
+            errors.add("image.name", "is empty");
+        }
+        if self.image.tag.is_empty() {
+            errors.add("image.tag", "is empty");
+        }
+        let host_network = self.network_mode.as_ref()
+            .map_or(false, |mode| mode == "host");
+        let mut service_names = HashSet::new();
+        let mut host_ports = HashSet::new();
+        for (i, service) in self.services.iter().enumerate() {
+            let path = format!("services[{}]", i);
+            if service.name.is_empty() {
+                errors.add(format!("{}.name", path), "is empty");
+            } else if !service_names.insert(&service.name) {
+                errors.add(format!("{}.name", path),
+                           format!("duplicate service name {:?}", service.name));
+            }
+            if service.port == 0 {
+                errors.add(format!("{}.port", path), "should not be 0");
+            }
+            match service.host_port {
+                Some(_) if host_network =>
+                    errors.add(format!("{}.host_port", path),
+                               "can't be used with network_mode host"),
+                Some(0) =>
+                    errors.add(format!("{}.host_port", path), "should not be 0"),
+                Some(port) if !host_ports.insert((port, service.udp)) =>
+                    errors.add(format!("{}.host_port", path),
+                               format!("port {} is already published", port)),
+                _ => ()
+            }
+            if service.check.interval == 0 {
+                errors.add(format!("{}.check.interval", path), "should not be 0");
+            }
+            if service.check.timeout > service.check.interval {
+                errors.add(format!("{}.check.timeout", path),
+                           format!("{} is greater than interval {}",
+                                   service.check.timeout, service.check.interval));
+            }
+        }
+        let mut env_names = HashSet::new();
+        for (i, env) in self.envs.iter().enumerate() {
+            let path = format!("envs[{}].name", i);
+            validate_env_name(&mut errors, path.clone(), &env.name);
+            if !env_names.insert(&env.name) {
+                errors.add(path, format!("duplicate env var {:?}", env.name));
+            }
+        }
+        for (i, env) in self.envs.iter().enumerate() {
+            match env.secret {
+                Some(ref secret) => {
+                    if !env.value.is_empty() {
+                        errors.add(format!("envs[{}]", i),
+                                   "only one of value and secret should be set");
+                    }
+                    validate_secret(&mut errors, format!("envs[{}].secret", i),
+                                    secret);
+                },
+                None => if let Err(e) = interpolate::check(&env.value) {
+                    errors.add(format!("envs[{}].value", i), e);
+                }
+            }
+        }
+        for (i, arg) in self.cmd.iter().enumerate() {
+            if let Err(e) = interpolate::check(arg) {
+                errors.add(format!("cmd[{}]", i), e);
+            }
+        }
+        if let Some(Err(e)) = self.host.as_ref().map(|host| interpolate::check(host)) {
+            errors.add("host", e);
+        }
+        let mut mount_points = HashSet::new();
+        for (i, volume) in self.volumes.iter().enumerate() {
+            let path = format!("volumes[{}]", i);
+            // with placeholders, source is checked after interpolation
+            let checked = match interpolate::has_placeholders(&volume.from) {
+                Ok(true) => Ok(()),
+                Ok(false) => volume.check_from(),
+                Err(e) => Err(e)
+            };
+            if let Err(e) = checked {
+                errors.add(format!("{}.from", path), e);
+            }
+            if !volume.to.starts_with('/') {
+                errors.add(format!("{}.to", path),
+                           format!("{:?} is not an absolute path", volume.to));
+            } else if !mount_points.insert(&volume.to) {
+                errors.add(format!("{}.to", path),
+                           format!("duplicate mount point {:?}", volume.to));
+            }
+        }
+        if let Some(ref log) = self.log {
+            if log.log_type.is_empty() {
+                errors.add("log.type", "is empty");
+            }
+            match log.config {
+                Some(serde_json::Value::Object(ref config)) => {
+                    for (key, value) in config.iter() {
+                        if value.as_string().is_none() {
+                            errors.add(format!("log.config.{}", key),
+                                       "should be a string");
+                        }
+                    }
+                },
+                Some(_) => errors.add("log.config", "should be an object of strings"),
+                None => ()
+            }
+        }
+        for (i, discovery) in self.discoveries.iter().enumerate() {
+            validate_env_name(&mut errors, format!("discoveries[{}].env", i),
+                              &discovery.env);
+            if discovery.service.is_empty() {
+                errors.add(format!("discoveries[{}].service", i), "is empty");
+            }
+        }
+        if errors.0.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Returns json with stable key ordering, which doesn't depend on
+    /// format or formatting of original spec
+    pub fn to_canonical_json(&self) -> String {
+        // json objects are BTreeMaps, so keys come out sorted
+        serde_json::to_string(&serde_json::to_value(self)).unwrap()
+    }
+
+    /// Returns short stable hash of spec contents
+    pub fn fingerprint(&self) -> String {
+        let mut hasher = SipHasher::new();
+        self.to_canonical_json().hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+/// Whether key can be written in yaml or toml without quotes
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| {
+        c.is_alphanumeric() || c == '_' || c == '-' || c == '.'
+    })
+}
+
+/// Text format of spec
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Json,
+    Yaml,
+    Toml
+}
+
+impl Format {
+    /// Guesses format from content: json starts with object, yaml has
+    /// unindented `key:` lines, toml has `key = value` or `[table]` lines,
+    /// everything else is yaml
+    pub fn sniff(s: &str) -> Format {
+        if s.trim_left().starts_with('{') {
+            return Format::Json;
+        }
+        let lines: Vec<&str> = s.lines()
+            .filter(|line| {
+                let line = line.trim();
+                !line.is_empty() && !line.starts_with('#')
+            })
+            .collect();
+        // spec is a mapping, so yaml always has top-level keys, and `=`
+        // may appear only in its values or block scalars
+        let is_yaml = lines.iter().any(|line| {
+            line.splitn(2, ':').next().map_or(false, |key| {
+                line.contains(':') && is_bare_key(key)
+            })
+        });
+        if is_yaml {
+            return Format::Yaml;
+        }
+        let is_toml = lines.iter()
+            .map(|line| line.trim())
+            .any(|line| {
+                (line.starts_with('[') && line.ends_with(']')
+                 && is_bare_key(line.trim_matches(|c: char| c == '[' || c == ']'))) ||
+                line.splitn(2, '=').next().map_or(false, |key| {
+                    line.contains('=') && is_bare_key(key.trim())
+                })
+            });
+        if is_toml { Format::Toml } else { Format::Yaml }
+    }
+
+    /// Returns format, which file extension suggests
+    pub fn from_path(path: &str) -> Option<Format> {
+        let lower = path.to_lowercase();
+        if lower.ends_with(".json") {
+            Some(Format::Json)
+        } else if lower.ends_with(".yaml") || lower.ends_with(".yml") {
+            Some(Format::Yaml)
+        } else if lower.ends_with(".toml") {
+            Some(Format::Toml)
+        } else {
+            None
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format::Json),
+            "yaml" | "yml" => Ok(Format::Yaml),
+            "toml" => Ok(Format::Toml),
+            other => Err(format!("Unknown spec format: {}", other))
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ParseError {
+    Json(serde_json::error::Error), // invalid json or spec structure
+    Yaml(String),
+    Toml(String)
+}
+
+impl error::Error for ParseError {
+    fn description(&self) -> &str {
+        match self {
+            &ParseError::Json(ref e) => error::Error::description(e),
+            &ParseError::Yaml(ref s) => s,
+            &ParseError::Toml(ref s) => s
+        }
+    }
+
+    fn cause(&self) -> Option<&error::Error> {
+        match self {
+            &ParseError::Json(ref e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            &ParseError::Json(ref e) => e.fmt(f),
+            &ParseError::Yaml(ref s) => write!(f, "Invalid yaml: {}", s),
+            &ParseError::Toml(ref s) => write!(f, "Invalid toml: {}", s)
+        }
+    }
+}
+
+impl Spec {
+    /// Parses spec in given format, or in sniffed one, when format is None
+    pub fn parse(s: &str, format: Option<Format>) -> Result<Spec, ParseError> {
+        // yaml and toml are converted to json values, so all formats share
+        // deserialization
+        let value = match format.unwrap_or_else(|| Format::sniff(s)) {
+            Format::Json => return serde_json::from_str(s).map_err(ParseError::Json),
+            Format::Yaml => try!(formats::yaml_to_json(s).map_err(ParseError::Yaml)),
+            Format::Toml => try!(formats::toml_to_json(s).map_err(ParseError::Toml))
+        };
+        serde_json::from_value(value).map_err(ParseError::Json)
+    }
 }
 
 impl FromStr for Spec {
-    type Err = serde_json::error::Error;
+    type Err = ParseError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        serde_json::from_str(&s)
+        Spec::parse(s, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+    use super::{Check, CheckMethod, EnvVar, Format, Image, Secret, Service, Spec,
+                Stop, Volume};
+
+    fn service(name: &str, port: u16, method: CheckMethod) -> Service {
+        Service{
+            name: name.to_string(),
+            port: port,
+            tags: vec!("v1".to_string()),
+            check: Check{method: method, interval: 10, timeout: 2},
+            udp: false,
+            host_port: None
+        }
+    }
+
+    fn env(name: &str, value: &str, secret: Option<Secret>) -> EnvVar {
+        EnvVar{name: name.to_string(), value: value.to_string(), secret: secret}
+    }
+
+    #[test]
+    fn canonical_json_round_trips() {
+        let mut dns = service("dns", 53, CheckMethod::Script("dig @localhost".to_string()));
+        dns.udp = true;
+        dns.host_port = Some(5353);
+        let spec = Spec{
+            image: Image{name: "registry/app".to_string(), tag: "1.2".to_string()},
+            cmd: vec!("serve".to_string(), "--port=8080".to_string()),
+            services: vec!(
+                service("web", 8080, CheckMethod::Http("http://127.0.0.1:8080/".to_string())),
+                service("admin", 8081, CheckMethod::HttpPath("/health".to_string())),
+                dns),
+            envs: vec!(
+                env("MODE", "production", None),
+                env("DB_PASSWORD", "", Some(Secret{
+                    consul: Some("secrets/app/db".to_string()),
+                    file: None
+                }))),
+            discoveries: vec!(),
+            name: Some("app".to_string()),
+            host: None,
+            user: None,
+            privileged: false,
+            network_mode: None,
+            stop: Stop::AfterTimeout(10),
+            kill_timeout: Some(30),
+            log: None,
+            volumes: vec!(
+                Volume{from: "/srv/app".to_string(), to: "/data".to_string(),
+                       read_only: true},
+                Volume{from: "cache".to_string(), to: "/cache".to_string(),
+                       read_only: false})
+        };
+        let json = spec.to_canonical_json();
+        assert!(json.contains(r#""stop":["AfterTimeout",10]"#), "{}", json);
+        assert_eq!(Spec::parse(&json, Some(Format::Json)).unwrap(), spec);
+    }
+
+    #[test]
+    fn stop_uses_yojson_encoding() {
+        assert_eq!(serde_json::to_string(&Stop::AfterTimeout(10)).unwrap(),
+                   r#"["AfterTimeout",10]"#);
+        assert_eq!(serde_json::to_string(&Stop::Before).unwrap(), r#"["Before"]"#);
+    }
+
+    #[test]
+    fn sniffs_json() {
+        assert_eq!(Format::sniff("\n  {\"image\": {}}"), Format::Json);
+    }
+
+    #[test]
+    fn sniffs_toml() {
+        assert_eq!(Format::sniff("cmd = [\"--port=80\"]\n[image]\nname = \"app\"\n"),
+                   Format::Toml);
+        assert_eq!(Format::sniff("# spec\n[[services]]\nname = \"web\"\n"),
+                   Format::Toml);
+    }
+
+    #[test]
+    fn sniffs_yaml_with_equals_signs() {
+        assert_eq!(Format::sniff("image:\n  name: app\n\
+cmd:\n  - --opt=value\n  - [a=b, c]\n"),
+                   Format::Yaml);
+        assert_eq!(Format::sniff(concat!("envs:\n",
+                                         "  - name: FLAGS\n",
+                                         "    value: |\n",
+                                         "      DEBUG=1\n",
+                                         "      [section]\n")),
+                   Format::Yaml);
+        assert_eq!(Format::sniff("- a=b\n"), Format::Yaml);
     }
 }
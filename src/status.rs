@@ -0,0 +1,64 @@
+// ext libs
+use serde_json;
+// std
+use std::collections::BTreeMap;
+use std::sync::mpsc;
+use std::thread;
+// internal
+use config::Config;
+use consul::Consul;
+use event::FailureReason;
+use spec::Spec;
+
+/// Snapshot of dispatcher, taken after every transition
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub state: String,
+    /// fingerprints of current and candidate specs
+    pub current: Option<String>,
+    pub candidate: Option<String>,
+    pub containers: Vec<String>,
+    /// host ports of current deploy's services, by service name
+    pub ports: BTreeMap<String, u16>,
+    /// spec of current deploy
+    pub spec: Option<Spec>,
+    pub last_error: Option<String>,
+    /// reason of the latest failed deploy
+    pub last_failure: Option<FailureReason>,
+    /// changes between deployed spec and the last received one
+    pub last_diff: Vec<String>,
+    /// recently received events, oldest first
+    pub events: Vec<String>,
+    /// when dispatcher entered current state
+    pub since: String,
+    pub updated_at: String
+}
+
+/// Writes every received status to consul key; config received from
+/// `reload` is used for following writes
+pub fn publish<T:AsRef<str> + Send>(consul: Consul, key: T,
+                                     statuses: mpsc::Receiver<Status>,
+                                     reload: mpsc::Receiver<Config>)
+                                     -> thread::JoinHandle<()>
+{
+    let thread_key = key.as_ref().to_owned();
+    thread::spawn(move || {
+        let mut consul = consul;
+        for status in statuses.iter() {
+            while let Ok(config) = reload.try_recv() {
+                info!("Reloading status publishing to {}", config.consul);
+                consul = Consul::from_config(&config);
+            }
+            let json = match serde_json::to_string(&status) {
+                Ok(json) => json,
+                Err(e) => {
+                    error!("Can't serialize status: {}", e);
+                    continue;
+                }
+            };
+            if let Err(e) = consul.put_key(&thread_key, &json) {
+                warn!("Can't publish status to {}: {}", thread_key, e);
+            }
+        }
+    })
+}
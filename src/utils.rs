@@ -24,3 +24,12 @@ macro_rules! ignore_result{
         $(let _ = $x)*
     }
 }
+
+/// Like `println!`, but writes to stderr, so errors don't mix with output
+#[macro_export]
+macro_rules! println_err{
+    ( $( $arg:tt )* ) => {{
+        use std::io::Write;
+        let _ = writeln!(&mut ::std::io::stderr(), $( $arg )*);
+    }}
+}